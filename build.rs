@@ -0,0 +1,4 @@
+fn main() {
+    // the migrations are embedded with `sqlx::migrate!`, which does not track the directory by itself
+    println!("cargo:rerun-if-changed=migrations");
+}
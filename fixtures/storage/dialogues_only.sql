@@ -0,0 +1,7 @@
+-- A database created by the very first versions of the bot, which only stored the dialogues
+CREATE TABLE teloxide_dialogues (
+    chat_id BIGINT PRIMARY KEY,
+    dialogue BLOB NOT NULL
+);
+INSERT INTO teloxide_dialogues VALUES (379529027, '{"Registered":{"session":"secret-session","email":"user@innopolis.university"}}');
+INSERT INTO teloxide_dialogues VALUES (42, '"ReceiveSession"');
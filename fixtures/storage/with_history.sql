@@ -0,0 +1,37 @@
+-- A database created right before the migrations were introduced, with the attendance history tables
+CREATE TABLE teloxide_dialogues (
+    chat_id BIGINT PRIMARY KEY,
+    dialogue BLOB NOT NULL
+);
+CREATE TABLE attendance_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    channel_id BIGINT NOT NULL,
+    message_id INTEGER NOT NULL,
+    activity_id INTEGER NOT NULL,
+    day INTEGER NOT NULL,
+    month INTEGER NOT NULL,
+    password TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE TABLE attendance_sessions (
+    event_id INTEGER NOT NULL REFERENCES attendance_events(id),
+    session_id INTEGER NOT NULL,
+    PRIMARY KEY (event_id, session_id)
+);
+CREATE TABLE attendance_outcomes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    event_id INTEGER NOT NULL REFERENCES attendance_events(id),
+    chat_id BIGINT NOT NULL,
+    session_id INTEGER,
+    outcome TEXT NOT NULL,
+    details TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX attendance_outcomes_chat_id ON attendance_outcomes (chat_id);
+
+INSERT INTO teloxide_dialogues VALUES (379529027, '{"Registered":{"session":"secret-session","email":"user@innopolis.university"}}');
+INSERT INTO attendance_events (channel_id, message_id, activity_id, day, month, password, created_at)
+    VALUES (-1001842503691, 17, 87610, 14, 2, 'qwerty', '2023-02-14 10:00:00');
+INSERT INTO attendance_sessions VALUES (1, 1337);
+INSERT INTO attendance_outcomes (event_id, chat_id, session_id, outcome, details, created_at)
+    VALUES (1, 379529027, 1337, 'marked', NULL, '2023-02-14 10:00:05');
@@ -0,0 +1,5 @@
+-- IF NOT EXISTS, as the databases created before the migrations were introduced already have the table
+CREATE TABLE IF NOT EXISTS teloxide_dialogues (
+    chat_id BIGINT PRIMARY KEY,
+    dialogue BLOB NOT NULL
+);
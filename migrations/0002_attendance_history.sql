@@ -0,0 +1,26 @@
+-- IF NOT EXISTS, as the databases created before the migrations were introduced may already have the tables
+CREATE TABLE IF NOT EXISTS attendance_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    channel_id BIGINT NOT NULL,
+    message_id INTEGER NOT NULL,
+    activity_id INTEGER NOT NULL,
+    day INTEGER NOT NULL,
+    month INTEGER NOT NULL,
+    password TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE TABLE IF NOT EXISTS attendance_sessions (
+    event_id INTEGER NOT NULL REFERENCES attendance_events(id),
+    session_id INTEGER NOT NULL,
+    PRIMARY KEY (event_id, session_id)
+);
+CREATE TABLE IF NOT EXISTS attendance_outcomes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    event_id INTEGER NOT NULL REFERENCES attendance_events(id),
+    chat_id BIGINT NOT NULL,
+    session_id INTEGER,
+    outcome TEXT NOT NULL,
+    details TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS attendance_outcomes_chat_id ON attendance_outcomes (chat_id);
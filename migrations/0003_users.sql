@@ -0,0 +1,12 @@
+-- The registered users, the `Registered` dialogue state only points here.
+-- The existing registrations are moved from the dialogue blobs at startup, as they are encrypted.
+CREATE TABLE users (
+    chat_id BIGINT PRIMARY KEY,
+    email TEXT NOT NULL,
+    -- encrypted with the storage key, like the dialogues
+    session BLOB NOT NULL,
+    registered_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_validated_at TIMESTAMP,
+    -- "valid", "invalid" or "error" (when the session could not be checked)
+    last_validation_result TEXT
+);
@@ -0,0 +1,9 @@
+-- The update channels a user has left. Everyone is subscribed to all the channels by default,
+-- so a user is only marked (or told they are not registered) for the channels not listed here.
+CREATE TABLE channel_opt_outs (
+    chat_id BIGINT NOT NULL,
+    channel_id BIGINT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (chat_id, channel_id)
+);
+CREATE INDEX channel_opt_outs_channel_id ON channel_opt_outs (channel_id);
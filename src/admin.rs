@@ -0,0 +1,141 @@
+use crate::moodle::Moodle;
+use crate::router::MyStorage;
+use crate::{config, metrics};
+use axum::extract;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, instrument};
+
+struct AdminState {
+    storage: Arc<MyStorage>,
+    moodle: Arc<Moodle>,
+}
+
+type AdminExtract = extract::State<Arc<AdminState>>;
+
+/// Serves the health checks, stats and prometheus metrics for the deployment tooling.
+pub async fn run(config: config::Admin, storage: Arc<MyStorage>, moodle: Arc<Moodle>) {
+    let app = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/moodlez", get(moodlez))
+        .route("/stats", get(stats))
+        .route("/metrics", get(render_metrics))
+        .with_state(Arc::new(AdminState { storage, moodle }));
+
+    info!("Serving the admin API on {}", config.listen_address);
+    if let Err(e) = axum::Server::bind(&config.listen_address)
+        .serve(app.into_make_service())
+        .await
+    {
+        error!("Admin API server failed: {:?}", e);
+    }
+}
+
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum Check {
+    Ok,
+    Failed { error: String },
+}
+
+impl<E: std::fmt::Display> From<Result<(), E>> for Check {
+    fn from(result: Result<(), E>) -> Self {
+        match result {
+            Ok(()) => Check::Ok,
+            Err(e) => Check::Failed {
+                error: e.to_string(),
+            },
+        }
+    }
+}
+
+/// How long to wait for moodle before reporting it as failed
+const MOODLE_PING_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Serialize)]
+struct Readiness {
+    database: Check,
+}
+
+/// Depends only on the database: the bot is still able to take updates while moodle is down
+#[instrument(skip_all)]
+async fn readyz(extract::State(state): AdminExtract) -> Response {
+    let readiness = Readiness {
+        database: state.storage.ping().await.into(),
+    };
+
+    let status = match readiness.database {
+        Check::Ok => StatusCode::OK,
+        Check::Failed { .. } => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (status, Json(readiness)).into_response()
+}
+
+/// Checks that moodle responds.
+///
+/// Each check takes a permit from the moodle rate limit, so this should not be polled often.
+#[instrument(skip_all)]
+async fn moodlez(extract::State(state): AdminExtract) -> Response {
+    let check = match tokio::time::timeout(MOODLE_PING_TIMEOUT, state.moodle.ping()).await {
+        Ok(result) => result.into(),
+        Err(_) => Check::Failed {
+            error: "timed out".to_string(),
+        },
+    };
+
+    let status = match check {
+        Check::Ok => StatusCode::OK,
+        Check::Failed { .. } => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (status, Json(check)).into_response()
+}
+
+#[derive(Serialize)]
+struct Stats {
+    /// Chats that have a dialogue with the bot, registered or not
+    chats: i64,
+    registered_users: i64,
+}
+
+#[instrument(skip_all)]
+async fn stats(extract::State(state): AdminExtract) -> Response {
+    // counted in SQL, so that the encrypted dialogues are not decrypted
+    let (chats, registered_users) =
+        tokio::join!(state.storage.count_dialogues(), state.storage.count_users());
+    match chats.and_then(|chats| Ok((chats, registered_users?))) {
+        Ok((chats, registered_users)) => Json(Stats {
+            chats,
+            registered_users,
+        })
+        .into_response(),
+        Err(e) => {
+            error!("Failed to count the chats: {:?}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+async fn render_metrics(extract::State(state): AdminExtract) -> Response {
+    match state.storage.count_users().await {
+        Ok(count) => metrics::REGISTERED_USERS.set(count),
+        Err(e) => {
+            error!("Failed to count users: {:?}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+    }
+
+    ([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], metrics::render()).into_response()
+}
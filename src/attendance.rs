@@ -1,10 +1,11 @@
+use crate::secret::Password;
 use std::fmt::Display;
 
 #[derive(Debug)]
 pub struct Attendance {
     pub day: u8,
     pub month: u8,
-    pub password: String,
+    pub password: Password,
 }
 
 impl Display for Attendance {
@@ -12,3 +13,49 @@ impl Display for Attendance {
         write!(f, "{}.{}: {}", self.day, self.month, self.password)
     }
 }
+
+/// What happened when we tried to mark attendance for a single chat.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MarkOutcome {
+    Marked,
+    AlreadyMarked,
+    /// No open session matched the date (already marked or closed)
+    NoMatchingSession,
+    SessionInvalid,
+    NotRegistered,
+    Error(String),
+}
+
+impl MarkOutcome {
+    /// Name of the outcome kind as stored in the database
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MarkOutcome::Marked => "marked",
+            MarkOutcome::AlreadyMarked => "already_marked",
+            MarkOutcome::NoMatchingSession => "no_matching_session",
+            MarkOutcome::SessionInvalid => "session_invalid",
+            MarkOutcome::NotRegistered => "not_registered",
+            MarkOutcome::Error(_) => "error",
+        }
+    }
+
+    /// Restores the outcome from the kind and details stored in the database
+    pub fn from_kind(kind: &str, details: Option<String>) -> Option<Self> {
+        Some(match kind {
+            "marked" => MarkOutcome::Marked,
+            "already_marked" => MarkOutcome::AlreadyMarked,
+            "no_matching_session" => MarkOutcome::NoMatchingSession,
+            "session_invalid" => MarkOutcome::SessionInvalid,
+            "not_registered" => MarkOutcome::NotRegistered,
+            "error" => MarkOutcome::Error(details.unwrap_or_default()),
+            _ => return None,
+        })
+    }
+
+    pub fn details(&self) -> Option<&str> {
+        match self {
+            MarkOutcome::Error(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! The LMS operations needed to mark the attendance, so that LMSes other than moodle can be supported.
+
+use crate::attendance::Attendance;
+use crate::moodle::{Moodle, MoodleError, SessionProbeResult};
+use crate::secret::{Password, Secret};
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use std::fmt::Display;
+use thiserror::Error;
+use url::Url;
+
+/// A user registered with their session in an LMS
+#[derive(Debug, Clone, Default)]
+pub struct BackendUser {
+    session: Secret<String>,
+    email: String,
+}
+
+impl BackendUser {
+    pub fn new(session: Secret<String>, email: String) -> Self {
+        Self { session, email }
+    }
+
+    pub fn session(&self) -> &Secret<String> {
+        &self.session
+    }
+
+    pub fn email(&self) -> &str {
+        &self.email
+    }
+}
+
+impl Display for BackendUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.email)
+    }
+}
+
+/// Result of checking the user's session
+#[derive(Debug)]
+pub enum Validation {
+    Invalid,
+    Valid {
+        email: String,
+        /// Has to be passed along with the marks (the CSRF token in moodle)
+        token: Secret<String>,
+    },
+}
+
+/// A session the user can mark themselves in
+#[derive(Debug, Clone)]
+pub struct AttendanceSession {
+    pub id: u32,
+    pub date: NaiveDate,
+}
+
+/// A session of the activity, along with the status recorded for the user
+#[derive(Debug)]
+pub struct SessionEntry {
+    pub date: NaiveDate,
+    /// Id of the session, present only if the user can mark themselves in it right now
+    pub session_id: Option<u32>,
+    /// Whether the user is recorded as present
+    pub present: bool,
+    /// Points for the session, as displayed by the LMS (like "2 / 2")
+    pub points: Option<String>,
+}
+
+impl SessionEntry {
+    pub fn session(&self) -> Option<AttendanceSession> {
+        self.session_id.map(|id| AttendanceSession {
+            id,
+            date: self.date,
+        })
+    }
+}
+
+/// An error returned from [`AttendanceBackend`] methods.
+#[derive(Debug, Error)]
+pub enum BackendError {
+    #[error("the session has expired")]
+    SessionExpired,
+    #[error("the LMS is rate limiting us")]
+    RateLimited,
+    #[error("incorrect password")]
+    WrongPassword,
+    #[error("the session is closed")]
+    SessionClosed,
+    #[error("could not find the \"Present\" status")]
+    NoPresentStatus,
+    /// The LMS refused the mark for a reason we could not classify
+    #[error("the LMS rejected the mark: {0}")]
+    Rejected(String),
+    /// The LMS (or the services we use along with it) could not be reached
+    #[error(transparent)]
+    Unreachable(Box<dyn std::error::Error + Send + Sync>),
+    /// The LMS responded with something we don't understand, likely after an update
+    #[error(transparent)]
+    Unexpected(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl BackendError {
+    /// Short name of the error variant, used as a metric label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BackendError::SessionExpired => "session_expired",
+            BackendError::RateLimited => "rate_limited",
+            BackendError::WrongPassword => "wrong_password",
+            BackendError::SessionClosed => "session_closed",
+            BackendError::NoPresentStatus => "no_present_status",
+            BackendError::Rejected(_) => "rejected",
+            BackendError::Unreachable(_) => "unreachable",
+            BackendError::Unexpected(_) => "unexpected",
+        }
+    }
+}
+
+impl From<MoodleError> for BackendError {
+    fn from(e: MoodleError) -> Self {
+        match e {
+            MoodleError::SessionExpired => BackendError::SessionExpired,
+            MoodleError::RateLimited => BackendError::RateLimited,
+            MoodleError::WrongPassword => BackendError::WrongPassword,
+            MoodleError::SessionClosed => BackendError::SessionClosed,
+            MoodleError::NoPresentStatus => BackendError::NoPresentStatus,
+            MoodleError::Rejected(message) => BackendError::Rejected(message),
+            e @ (MoodleError::Network(_) | MoodleError::Status(_) | MoodleError::Extender(_)) => {
+                BackendError::Unreachable(e.into())
+            }
+            e @ (MoodleError::UnexpectedRedirect(_)
+            | MoodleError::LayoutChanged { .. }
+            | MoodleError::InvalidResponse(_)
+            | MoodleError::Url(_)
+            | MoodleError::WebService { .. }) => BackendError::Unexpected(e.into()),
+        }
+    }
+}
+
+pub type Result<T, E = BackendError> = std::result::Result<T, E>;
+
+/// An LMS the attendance can be marked in
+#[async_trait]
+pub trait AttendanceBackend: Send + Sync {
+    /// Makes a user from their session, returning `None` if the session is not valid
+    async fn register(&self, session: Secret<String>) -> Result<Option<BackendUser>>;
+
+    /// Keeps the user's session from expiring, returning `false` if it is not valid anymore
+    async fn keep_alive(&self, user: &BackendUser) -> Result<bool>;
+
+    /// Checks that the user's session is still valid, getting the token to mark with
+    async fn validate_user(&self, user: &BackendUser) -> Result<Validation>;
+
+    /// Lists the sessions of the activity on the date of the attendance, along with the statuses recorded for the user
+    async fn list_sessions(
+        &self,
+        activity_id: u32,
+        user: &BackendUser,
+        attendance: &Attendance,
+    ) -> Result<Vec<SessionEntry>>;
+
+    /// Submits the attendance password for the session, marking the user as present
+    async fn mark(
+        &self,
+        user: &BackendUser,
+        token: &Secret<String>,
+        session_id: u32,
+        password: &Password,
+    ) -> Result<()>;
+
+    /// Page the users can mark themselves on when the bot fails to
+    fn activity_url(&self, activity_id: u32) -> Result<Url>;
+
+    fn session_url(&self, session_id: u32) -> Result<Url>;
+}
+
+#[async_trait]
+impl AttendanceBackend for Moodle {
+    async fn register(&self, session: Secret<String>) -> Result<Option<BackendUser>> {
+        Ok(self.make_user(session).await?)
+    }
+
+    async fn keep_alive(&self, user: &BackendUser) -> Result<bool> {
+        Ok(self.extend_session(user).await?.is_some())
+    }
+
+    async fn validate_user(&self, user: &BackendUser) -> Result<Validation> {
+        Ok(match self.check_user(user).await? {
+            SessionProbeResult::Invalid => Validation::Invalid,
+            SessionProbeResult::Valid {
+                email,
+                csrf_session,
+            } => Validation::Valid {
+                email,
+                token: csrf_session,
+            },
+        })
+    }
+
+    async fn list_sessions(
+        &self,
+        activity_id: u32,
+        user: &BackendUser,
+        attendance: &Attendance,
+    ) -> Result<Vec<SessionEntry>> {
+        Ok(self
+            .get_attendance_report(activity_id, user)
+            .await?
+            .into_iter()
+            .filter(|entry| entry.matches(attendance))
+            .map(|entry| SessionEntry {
+                present: entry.is_present(),
+                date: entry.date,
+                session_id: entry.session_id,
+                points: entry.points,
+            })
+            .collect())
+    }
+
+    async fn mark(
+        &self,
+        user: &BackendUser,
+        token: &Secret<String>,
+        session_id: u32,
+        password: &Password,
+    ) -> Result<()> {
+        Ok(self.mark_present(user, token, session_id, password).await?)
+    }
+
+    fn activity_url(&self, activity_id: u32) -> Result<Url> {
+        Ok(self.make_attendance_url(activity_id)?)
+    }
+
+    fn session_url(&self, session_id: u32) -> Result<Url> {
+        Ok(self.make_session_url(session_id)?)
+    }
+}
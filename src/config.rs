@@ -1,6 +1,10 @@
 use anyhow::Context;
 use camino::Utf8PathBuf;
+use serde::de::IntoDeserializer;
 use serde::{de, Deserialize, Deserializer};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
 use std::str::FromStr;
 use std::time::Duration;
 use teloxide::types::ChatId;
@@ -29,6 +33,11 @@ pub struct Config {
     pub moodle_extender: MoodleExtender,
     pub updater: Updater,
     pub bot: Bot,
+    /// The admin HTTP server is not started if this is not set
+    #[serde(default)]
+    pub admin: Option<Admin>,
+    #[serde(default)]
+    pub tracing: Tracing,
 }
 
 impl Config {
@@ -38,7 +47,70 @@ impl Config {
             .unwrap_or_else(|_| Utf8PathBuf::from_str("config.yaml").unwrap());
 
         let config = std::fs::read_to_string(config_path).context("Reading config file")?;
-        serde_yaml::from_str(&config).context("Parsing config file")
+        let config: Config = serde_yaml::from_str(&config).context("Parsing config file")?;
+        config.bot.validate().context("Validating bot config")?;
+
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Tracing {
+    /// Can be overridden with the `OTEL_TRACES_EXPORTER` env var
+    #[serde(default)]
+    pub exporter: TraceExporter,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default)]
+    pub redaction: RedactionPolicy,
+}
+
+/// Which secrets may appear in logs and traces. Session cookies never do.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RedactionPolicy {
+    /// Attendance passwords and telegram message payloads are redacted too
+    #[default]
+    Strict,
+    /// Attendance passwords and outgoing telegram message payloads are logged, useful for debugging
+    RevealPasswords,
+}
+
+/// How the logs are written to stdout
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// Human-readable, one line per event
+    #[default]
+    Compact,
+    /// JSON lines with the span fields flattened and the otel trace id
+    Json,
+}
+
+/// Where to send the spans to
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TraceExporter {
+    /// OTLP collector, configured with the standard `OTEL_EXPORTER_OTLP_*` env vars
+    #[default]
+    Otlp,
+    /// Finished spans are written to stdout as JSON lines
+    StdoutJson,
+    /// Spans are only logged
+    None,
+}
+
+impl TraceExporter {
+    /// Reads the exporter from the `OTEL_TRACES_EXPORTER` env var, if it is set
+    pub fn from_env() -> anyhow::Result<Option<TraceExporter>> {
+        let Ok(value) = std::env::var("OTEL_TRACES_EXPORTER") else {
+            return Ok(None);
+        };
+
+        let de: de::value::StrDeserializer<de::value::Error> = value.as_str().into_deserializer();
+        TraceExporter::deserialize(de)
+            .map(Some)
+            .with_context(|| format!("Parsing OTEL_TRACES_EXPORTER={:?}", value))
     }
 }
 
@@ -55,12 +127,40 @@ pub struct Moodle {
     pub rpm: u32,
     pub max_burst: u32,
     pub user_agent: String,
+    #[serde(default)]
+    pub retry: Retry,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct MoodleExtender {
     #[serde(deserialize_with = "deserialize_url")]
     pub base_url: Url,
+    #[serde(default)]
+    pub retry: Retry,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Retry {
+    /// Total number of attempts, including the first one
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for each next one
+    #[serde(with = "humantime_serde")]
+    pub backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Admin {
+    /// Local address to serve the health checks and stats on
+    pub listen_address: SocketAddr,
 }
 
 #[derive(Debug, Deserialize)]
@@ -73,10 +173,59 @@ pub struct Updater {
 pub struct Bot {
     pub update_channels: Vec<BotChannel>,
     pub super_users: Vec<ChatId>,
+    /// How many users to mark concurrently when a password is posted
+    #[serde(default = "default_mark_concurrency")]
+    pub mark_concurrency: NonZeroUsize,
+    #[serde(default)]
+    pub listener: Listener,
+}
+
+/// How the bot receives updates from telegram
+#[derive(Debug, Default, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Listener {
+    /// Long polling, does not require the bot to be reachable from the internet
+    #[default]
+    Polling,
+    Webhook(Webhook),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Webhook {
+    /// Local address to listen on
+    pub listen_address: SocketAddr,
+    /// Public URL telegram will send the updates to
+    #[serde(deserialize_with = "deserialize_url")]
+    pub url: Url,
+    /// File with the secret token telegram will send along with the updates
+    #[serde(deserialize_with = "deserialize_path")]
+    pub secret_token_file: Utf8PathBuf,
+}
+
+impl Bot {
+    fn validate(&self) -> anyhow::Result<()> {
+        // the users /join and /leave the channels by name, ignoring the case
+        let mut names = HashSet::new();
+        for channel in &self.update_channels {
+            anyhow::ensure!(
+                names.insert(channel.name.to_ascii_lowercase()),
+                "Update channel name {:?} is used more than once",
+                channel.name
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn default_mark_concurrency() -> NonZeroUsize {
+    NonZeroUsize::new(16).unwrap()
 }
 
 #[derive(Debug, Deserialize)]
 pub struct BotChannel {
     pub id: ChatId,
+    /// Short name the users /join and /leave the channel by
+    pub name: String,
     pub activity_id: u32,
 }
@@ -0,0 +1,186 @@
+use crate::storage::ChatSerializer;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::fmt::{Debug, Display};
+use teloxide::dispatching::dialogue::Serializer;
+use teloxide::types::ChatId;
+use thiserror::Error;
+
+/// Prefix of the encrypted data. Plaintext JSON can't start with a NUL byte, so the two are easy to tell apart.
+const MAGIC: &[u8] = b"\0enc1";
+const NONCE_LEN: usize = 12;
+
+/// A [`Serializer`] wrapper that encrypts the serialized dialogues with ChaCha20-Poly1305.
+///
+/// The data is stored as `MAGIC || nonce || ciphertext`. Data without the magic prefix is assumed to be
+/// plaintext stored before the encryption was enabled, and is passed to the inner serializer as is.
+///
+/// The chat id the data belongs to is authenticated along with it, so that a row copied to another chat
+/// fails to decrypt instead of giving that chat someone else's session.
+pub struct Encrypted<S> {
+    inner: S,
+    cipher: ChaCha20Poly1305,
+}
+
+#[derive(Debug, Error)]
+pub enum EncryptedError<E>
+where
+    E: Debug + Display,
+{
+    #[error("{0}")]
+    Inner(E),
+    #[error("could not encrypt the dialogue")]
+    Encryption,
+    #[error("could not decrypt the dialogue (is the storage key right?)")]
+    Decryption,
+}
+
+/// Parses a hex-encoded 256-bit key, as generated by `openssl rand -hex 32`
+pub fn parse_key(hex_key: &str) -> anyhow::Result<Key> {
+    let mut key = Key::default();
+    hex::decode_to_slice(hex_key.trim(), &mut key)
+        .map_err(|e| anyhow::anyhow!("The key should be 64 hex digits: {}", e))?;
+    Ok(key)
+}
+
+impl<S> Encrypted<S> {
+    pub fn new(inner: S, key: &Key) -> Self {
+        Self {
+            inner,
+            cipher: ChaCha20Poly1305::new(key),
+        }
+    }
+
+    pub fn is_encrypted(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    pub fn encrypt(
+        &self,
+        chat_id: ChatId,
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, chacha20poly1305::Error> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher.encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: &chat_id.0.to_le_bytes(),
+            },
+        )?;
+
+        Ok([MAGIC, nonce.as_slice(), &ciphertext].concat())
+    }
+
+    pub fn decrypt(
+        &self,
+        chat_id: ChatId,
+        data: &[u8],
+    ) -> Result<Vec<u8>, chacha20poly1305::Error> {
+        let data = data.strip_prefix(MAGIC).ok_or(chacha20poly1305::Error)?;
+        if data.len() < NONCE_LEN {
+            return Err(chacha20poly1305::Error);
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+        self.cipher.decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &chat_id.0.to_le_bytes(),
+            },
+        )
+    }
+}
+
+impl<S, D> ChatSerializer<D> for Encrypted<S>
+where
+    S: Serializer<D>,
+    S::Error: Debug + Display,
+{
+    type Error = EncryptedError<S::Error>;
+
+    fn serialize(&self, chat_id: ChatId, val: &D) -> Result<Vec<u8>, Self::Error> {
+        let plaintext = self.inner.serialize(val).map_err(EncryptedError::Inner)?;
+        self.encrypt(chat_id, &plaintext)
+            .map_err(|_| EncryptedError::Encryption)
+    }
+
+    fn deserialize(&self, chat_id: ChatId, data: &[u8]) -> Result<D, Self::Error> {
+        if !Self::is_encrypted(data) {
+            return self.inner.deserialize(data).map_err(EncryptedError::Inner);
+        }
+
+        let plaintext = self
+            .decrypt(chat_id, data)
+            .map_err(|_| EncryptedError::Decryption)?;
+        self.inner
+            .deserialize(&plaintext)
+            .map_err(EncryptedError::Inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::State;
+    use teloxide::dispatching::dialogue::serializer::Json;
+
+    fn key(byte: u8) -> Key {
+        [byte; 32].into()
+    }
+
+    #[test]
+    fn round_trips() {
+        let serializer = Encrypted::new(Json, &key(1));
+
+        let data = serializer
+            .serialize(ChatId(42), &State::ReceiveSession)
+            .unwrap();
+        assert!(Encrypted::<Json>::is_encrypted(&data));
+        assert!(!data.windows(14).any(|w| w == b"ReceiveSession"));
+
+        let state: State = serializer.deserialize(ChatId(42), &data).unwrap();
+        assert!(matches!(state, State::ReceiveSession));
+    }
+
+    #[test]
+    fn detects_encrypted_data() {
+        let serializer = Encrypted::new(Json, &key(1));
+
+        assert!(Encrypted::<Json>::is_encrypted(
+            &serializer.encrypt(ChatId(42), b"").unwrap()
+        ));
+        assert!(!Encrypted::<Json>::is_encrypted(b"\"Registered\""));
+        assert!(!Encrypted::<Json>::is_encrypted(b""));
+    }
+
+    #[test]
+    fn passes_plaintext_through() {
+        let serializer = Encrypted::new(Json, &key(1));
+
+        let state: State = serializer
+            .deserialize(ChatId(42), b"\"Registered\"")
+            .unwrap();
+        assert!(matches!(state, State::Registered));
+    }
+
+    #[test]
+    fn fails_with_wrong_key() {
+        let data = Encrypted::new(Json, &key(1))
+            .serialize(ChatId(42), &State::Registered)
+            .unwrap();
+
+        let result: Result<State, _> = Encrypted::new(Json, &key(2)).deserialize(ChatId(42), &data);
+        assert!(matches!(result, Err(EncryptedError::Decryption)));
+    }
+
+    #[test]
+    fn fails_for_another_chat() {
+        let serializer = Encrypted::new(Json, &key(1));
+        let data = serializer.encrypt(ChatId(42), b"session").unwrap();
+
+        assert!(serializer.decrypt(ChatId(43), &data).is_err());
+        assert_eq!(serializer.decrypt(ChatId(42), &data).unwrap(), b"session");
+    }
+}
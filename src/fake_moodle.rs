@@ -0,0 +1,371 @@
+//! A fake moodle (and moodle extender) server serving the recorded pages from `fixtures/moodle`, for the tests.
+
+use crate::config;
+use crate::moodle::Moodle;
+use crate::moodle_extender::MoodleExtender;
+use axum::body::StreamBody;
+use axum::extract::{Query, State};
+use axum::http::header::{COOKIE, LOCATION};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Form, Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use url::Url;
+
+/// The only session cookie the fake moodle accepts
+pub const SESSION: &str = "fake-moodle-session";
+/// The CSRF token from the recorded pages
+pub const SESSKEY: &str = "Xq3kEuR1pD";
+/// The email from the recorded profile page
+pub const EMAIL: &str = "i.ivanov@innopolis.university";
+/// The user id from the recorded profile page
+pub const USER_ID: u32 = 4125;
+pub const ACTIVITY_ID: u32 = 87610;
+/// The only session open for marking in the recorded report
+pub const SESSION_ID: u32 = 40712;
+pub const PRESENT_STATUS_ID: u32 = 1203;
+pub const PASSWORD: &str = "qwerty";
+
+const PROFILE_PAGE: &str = include_str!("../fixtures/moodle/profile/en.html");
+const REPORT_PAGE: &str = include_str!("../fixtures/moodle/report/en.html");
+/// The same report after the user has marked themselves in [`SESSION_ID`]
+const MARKED_REPORT_PAGE: &str = include_str!("../fixtures/moodle/report/en_marked.html");
+const SESSION_FORM_PAGE: &str = include_str!("../fixtures/moodle/session/en.html");
+
+/// A mark submitted to the fake moodle
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct MarkForm {
+    pub sesskey: String,
+    pub sessid: u32,
+    pub studentpassword: String,
+    pub status: u32,
+}
+
+#[derive(Default)]
+struct FakeState {
+    marks: Mutex<Vec<MarkForm>>,
+    /// Moodle keeps the notifications in the session and shows them on the next page
+    notification: Mutex<Option<String>>,
+    /// The web service functions called so far
+    ajax_calls: Mutex<Vec<String>>,
+    ajax_disabled: AtomicBool,
+    ajax_broken: AtomicBool,
+}
+
+pub struct FakeMoodle {
+    base_url: Url,
+    state: Arc<FakeState>,
+}
+
+fn has_session(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .any(|v| v.trim() == format!("MoodleSession={}", SESSION))
+}
+
+fn redirect(location: &str) -> Response {
+    (StatusCode::SEE_OTHER, [(LOCATION, location.to_string())]).into_response()
+}
+
+fn login_redirect() -> Response {
+    redirect("/login/index.php")
+}
+
+async fn login() -> Html<&'static str> {
+    Html("<html><body><form id=\"login\"></form></body></html>")
+}
+
+async fn profile(headers: HeaderMap) -> Response {
+    if !has_session(&headers) {
+        return login_redirect();
+    }
+    Html(PROFILE_PAGE).into_response()
+}
+
+#[derive(Deserialize)]
+struct ViewQuery {
+    id: u32,
+    view: u32,
+}
+
+async fn view(
+    State(state): State<Arc<FakeState>>,
+    headers: HeaderMap,
+    Query(query): Query<ViewQuery>,
+) -> Response {
+    if !has_session(&headers) {
+        return login_redirect();
+    }
+    if query.id != ACTIVITY_ID || query.view != 5 {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    if state.marks.lock().unwrap().is_empty() {
+        Html(REPORT_PAGE).into_response()
+    } else {
+        Html(MARKED_REPORT_PAGE).into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct SessionQuery {
+    sessid: u32,
+}
+
+async fn session_form(
+    State(state): State<Arc<FakeState>>,
+    headers: HeaderMap,
+    Query(query): Query<SessionQuery>,
+) -> Response {
+    if !has_session(&headers) {
+        return login_redirect();
+    }
+    if query.sessid != SESSION_ID {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let notification = state
+        .notification
+        .lock()
+        .unwrap()
+        .take()
+        .map(|message| {
+            format!(
+                "<div class=\"alert alert-danger alert-block fade in alert-dismissible\" role=\"alert\">{}\
+                <button type=\"button\" class=\"close\" data-dismiss=\"alert\"><span aria-hidden=\"true\">&times;</span>\
+                <span class=\"sr-only\">Dismiss this notification</span></button></div>",
+                message
+            )
+        })
+        .unwrap_or_default();
+
+    Html(SESSION_FORM_PAGE.replace("<!--NOTIFICATIONS-->", &notification)).into_response()
+}
+
+async fn submit_mark(
+    State(state): State<Arc<FakeState>>,
+    headers: HeaderMap,
+    Form(form): Form<MarkForm>,
+) -> Response {
+    if !has_session(&headers) {
+        return login_redirect();
+    }
+    if form.sesskey != SESSKEY || form.sessid != SESSION_ID {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    if form.studentpassword != PASSWORD {
+        *state.notification.lock().unwrap() =
+            Some("Incorrect password, please try again".to_string());
+        return redirect(&format!(
+            "/mod/attendance/attendance.php?sessid={}",
+            form.sessid
+        ));
+    }
+
+    state.marks.lock().unwrap().push(form);
+    redirect(&format!("/mod/attendance/view.php?id={}", ACTIVITY_ID))
+}
+
+#[derive(Deserialize)]
+struct AjaxQuery {
+    sesskey: String,
+}
+
+#[derive(Deserialize)]
+struct AjaxCall {
+    methodname: String,
+    args: Value,
+}
+
+fn ajax_exception(errorcode: &str, message: &str) -> Value {
+    json!([{
+        "error": true,
+        "exception": { "message": message, "errorcode": errorcode, "link": "", "moreinfourl": "" },
+    }])
+}
+
+/// Serves the web service functions the bot uses, with the same data as the recorded pages
+async fn ajax(
+    State(state): State<Arc<FakeState>>,
+    headers: HeaderMap,
+    Query(query): Query<AjaxQuery>,
+    Json(calls): Json<Vec<AjaxCall>>,
+) -> Response {
+    if state.ajax_disabled.load(Ordering::Relaxed) {
+        return Json(json!({
+            "error": "Web service is not available (it doesn't exist or might be disabled)",
+            "errorcode": "servicenotavailable",
+        }))
+        .into_response();
+    }
+    if state.ajax_broken.load(Ordering::Relaxed) {
+        let body = futures::stream::iter([
+            Ok("[{\"error\": false, "),
+            Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "connection lost",
+            )),
+        ]);
+        return StreamBody::new(body).into_response();
+    }
+
+    let [call] = &calls[..] else {
+        panic!(
+            "the bot makes a single call per request, got {}",
+            calls.len()
+        );
+    };
+    state
+        .ajax_calls
+        .lock()
+        .unwrap()
+        .push(call.methodname.clone());
+
+    if !has_session(&headers) {
+        return Json(ajax_exception(
+            "servicerequireslogin",
+            "Web service is not available, you are not logged in",
+        ))
+        .into_response();
+    }
+    if query.sesskey != SESSKEY {
+        return Json(ajax_exception(
+            "invalidsesskey",
+            "Your session has most likely timed out",
+        ))
+        .into_response();
+    }
+
+    let data = match (call.methodname.as_str(), &call.args) {
+        ("core_user_get_users_by_field", args)
+            if args == &json!({ "field": "id", "values": [USER_ID] }) =>
+        {
+            json!([{ "id": USER_ID, "fullname": "Ivan Ivanov", "email": EMAIL }])
+        }
+        ("mod_attendance_get_session", args) if args == &json!({ "sessionid": SESSION_ID }) => {
+            json!({
+                "id": SESSION_ID,
+                "statuses": [
+                    { "id": 1203, "acronym": "P", "description": "Present", "grade": 2.0 },
+                    { "id": 1204, "acronym": "L", "description": "Late", "grade": 1.0 },
+                    { "id": 1205, "acronym": "E", "description": "Excused", "grade": 1.0 },
+                    { "id": 1206, "acronym": "A", "description": "Absent", "grade": 0.0 },
+                ],
+            })
+        }
+        _ => {
+            return Json(ajax_exception(
+                "invalidparameter",
+                "Invalid parameter value detected",
+            ))
+            .into_response()
+        }
+    };
+
+    Json(json!([{ "error": false, "data": data }])).into_response()
+}
+
+#[derive(Deserialize)]
+struct ExtendRequest {
+    moodle_session: String,
+}
+
+#[derive(Serialize)]
+struct ExtendResponse {
+    email: Option<&'static str>,
+}
+
+async fn extend_session(Json(request): Json<ExtendRequest>) -> Json<ExtendResponse> {
+    Json(ExtendResponse {
+        email: (request.moodle_session == SESSION).then_some(EMAIL),
+    })
+}
+
+fn no_retry() -> config::Retry {
+    config::Retry {
+        attempts: 1,
+        backoff: Duration::ZERO,
+    }
+}
+
+impl FakeMoodle {
+    /// Starts the server on a random local port
+    pub async fn start() -> Self {
+        let state = Arc::new(FakeState::default());
+
+        let app = Router::new()
+            .route("/login/index.php", get(login))
+            .route("/user/profile.php", get(profile))
+            .route("/mod/attendance/view.php", get(view))
+            .route(
+                "/mod/attendance/attendance.php",
+                get(session_form).post(submit_mark),
+            )
+            .route("/lib/ajax/service.php", post(ajax))
+            .route("/extend-session", post(extend_session))
+            .with_state(state.clone());
+
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let base_url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        Self { base_url, state }
+    }
+
+    /// Moodle config pointing to this server, with no retries and a generous rate limit
+    pub fn config(&self) -> config::Moodle {
+        config::Moodle {
+            base_url: self.base_url.clone(),
+            rpm: 60_000,
+            max_burst: 1000,
+            user_agent: "historia-tests".to_string(),
+            retry: no_retry(),
+        }
+    }
+
+    /// Makes a moodle client talking to this server, the extender requests are served by it too
+    pub async fn client(&self) -> Moodle {
+        let extender = MoodleExtender::new(&config::MoodleExtender {
+            base_url: self.base_url.clone(),
+            retry: no_retry(),
+        })
+        .await
+        .unwrap();
+
+        Moodle::new(&self.config(), extender).await.unwrap()
+    }
+
+    /// Makes the web service fail, as if it was disabled by the administrator
+    pub fn disable_ajax(&self) {
+        self.state.ajax_disabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Makes the web service drop the connection in the middle of the response
+    pub fn break_ajax(&self) {
+        self.state.ajax_broken.store(true, Ordering::Relaxed);
+    }
+
+    /// The web service functions called so far
+    pub fn ajax_calls(&self) -> Vec<String> {
+        self.state.ajax_calls.lock().unwrap().clone()
+    }
+
+    /// The marks successfully submitted so far
+    pub fn marks(&self) -> Vec<MarkForm> {
+        self.state.marks.lock().unwrap().clone()
+    }
+}
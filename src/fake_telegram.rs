@@ -0,0 +1,213 @@
+//! A fake telegram bot API server recording the requests the bot makes, for the tests.
+//!
+//! The updates are not delivered through the API: the tests make them with [`FakeTelegram::message`]
+//! and [`FakeTelegram::channel_post`] and feed them to the handlers directly.
+
+use axum::extract::{Path, State};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use teloxide::types::{ChatId, Me, Update};
+use teloxide::Bot;
+use url::Url;
+
+const BOT_ID: i64 = 5_000_000_000;
+const TOKEN: &str = "5000000000:FAKE-TOKEN";
+/// Date of all the messages, 2023-02-14 10:00:00 UTC
+const DATE: i64 = 1_676_368_800;
+
+/// A request made by the bot
+#[derive(Debug, Clone)]
+pub struct BotRequest {
+    pub method: String,
+    pub payload: Value,
+}
+
+impl BotRequest {
+    pub fn chat_id(&self) -> Option<ChatId> {
+        self.payload["chat_id"].as_i64().map(ChatId)
+    }
+
+    pub fn text(&self) -> &str {
+        self.payload["text"].as_str().unwrap_or_default()
+    }
+}
+
+#[derive(Default)]
+struct FakeState {
+    requests: Mutex<Vec<BotRequest>>,
+    next_id: AtomicI32,
+    /// The chats that blocked the bot, sending messages to them fails
+    blocked: Mutex<HashSet<i64>>,
+}
+
+impl FakeState {
+    fn next_id(&self) -> i32 {
+        self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+pub struct FakeTelegram {
+    api_url: Url,
+    state: Arc<FakeState>,
+}
+
+fn chat(id: i64) -> Value {
+    if id < 0 {
+        json!({ "id": id, "type": "channel", "title": "Attendance passwords" })
+    } else {
+        json!({ "id": id, "type": "private", "first_name": "Ivan", "last_name": "Ivanov" })
+    }
+}
+
+/// Responds like telegram would, with the sent or edited message where there is one
+async fn method(
+    State(state): State<Arc<FakeState>>,
+    Path((_token, method)): Path<(String, String)>,
+    Json(payload): Json<Value>,
+) -> Json<Value> {
+    // teloxide sends the method names capitalized, telegram does not care, but the docs use camelCase
+    let mut chars = method.chars();
+    let method = chars
+        .next()
+        .map(|first| first.to_ascii_lowercase().to_string() + chars.as_str())
+        .unwrap_or_default();
+
+    let blocked = payload["chat_id"]
+        .as_i64()
+        .is_some_and(|chat_id| state.blocked.lock().unwrap().contains(&chat_id));
+    if blocked {
+        state
+            .requests
+            .lock()
+            .unwrap()
+            .push(BotRequest { method, payload });
+        return Json(json!({
+            "ok": false,
+            "error_code": 403,
+            "description": "Forbidden: bot was blocked by the user",
+        }));
+    }
+
+    let result = match method.as_str() {
+        "sendMessage" | "editMessageText" => {
+            let message_id = match payload["message_id"].as_i64() {
+                Some(id) => id as i32,
+                None => state.next_id(),
+            };
+            json!({
+                "message_id": message_id,
+                "date": DATE,
+                "chat": chat(payload["chat_id"].as_i64().unwrap_or_default()),
+                "from": { "id": BOT_ID, "is_bot": true, "first_name": "Historia", "username": "historia_bot" },
+                "text": payload["text"],
+            })
+        }
+        _ => json!(true),
+    };
+
+    state
+        .requests
+        .lock()
+        .unwrap()
+        .push(BotRequest { method, payload });
+
+    Json(json!({ "ok": true, "result": result }))
+}
+
+impl FakeTelegram {
+    /// Starts the server on a random local port
+    pub async fn start() -> Self {
+        let state = Arc::new(FakeState::default());
+
+        let app = Router::new()
+            .route("/:token/:method", post(method))
+            .with_state(state.clone());
+
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let api_url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        Self { api_url, state }
+    }
+
+    /// Makes a bot talking to this server
+    pub fn bot(&self) -> Bot {
+        Bot::new(TOKEN).set_api_url(self.api_url.clone())
+    }
+
+    /// What `getMe` would return
+    pub fn me(&self) -> Me {
+        serde_json::from_value(json!({
+            "id": BOT_ID,
+            "is_bot": true,
+            "first_name": "Historia",
+            "username": "historia_bot",
+            "can_join_groups": false,
+            "can_read_all_group_messages": false,
+            "supports_inline_queries": false,
+        }))
+        .unwrap()
+    }
+
+    /// Makes an update with a text message from a user
+    pub fn message(&self, ChatId(chat_id): ChatId, text: &str) -> Update {
+        self.update(json!({
+            "message": {
+                "message_id": self.state.next_id(),
+                "date": DATE,
+                "chat": chat(chat_id),
+                "from": { "id": chat_id, "is_bot": false, "first_name": "Ivan", "last_name": "Ivanov" },
+                "text": text,
+            }
+        }))
+    }
+
+    /// Makes an update with a post in a channel
+    pub fn channel_post(&self, ChatId(channel_id): ChatId, text: &str) -> Update {
+        self.update(json!({
+            "channel_post": {
+                "message_id": self.state.next_id(),
+                "date": DATE,
+                "chat": chat(channel_id),
+                "sender_chat": chat(channel_id),
+                "text": text,
+            }
+        }))
+    }
+
+    fn update(&self, kind: Value) -> Update {
+        // teloxide expects the update id to come before the update kind, which `Value` does not preserve
+        #[derive(Serialize)]
+        struct RawUpdate {
+            update_id: i32,
+            #[serde(flatten)]
+            kind: Value,
+        }
+
+        let update = RawUpdate {
+            update_id: self.state.next_id(),
+            kind,
+        };
+        serde_json::from_str(&serde_json::to_string(&update).unwrap()).unwrap()
+    }
+
+    /// Makes the requests to the chat fail, as if the user blocked the bot
+    pub fn block(&self, ChatId(chat_id): ChatId) {
+        self.state.blocked.lock().unwrap().insert(chat_id);
+    }
+
+    /// Returns the requests made since the last call
+    pub fn take_requests(&self) -> Vec<BotRequest> {
+        std::mem::take(&mut self.state.requests.lock().unwrap())
+    }
+}
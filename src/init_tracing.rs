@@ -1,24 +1,22 @@
 // 1. Run`cargo add opentelemetry opentelemetry-otlp tracing-opentelemetry tracing-subscriber --features=opentelemetry/rt-tokio,tracing-subscriber/env-filter`
-// 2. add `init_tracing::init_tracing().context("Setting up the opentelemetry exporter")?;` to main.rs
+// 2. add `init_tracing::init_tracing(&config.tracing).context("Setting up tracing")?;` to main.rs
 
+use crate::config::{self, LogFormat, TraceExporter};
+use crate::json_log_format::{JsonLogFormat, OtelIdsLayer};
+use crate::stdout_exporter::StdoutJsonExporter;
 use anyhow::{Context, Result};
 use opentelemetry::sdk::resource::{EnvResourceDetector, SdkProvidedResourceDetector};
 use opentelemetry::sdk::{trace as sdktrace, Resource};
+use opentelemetry::trace::TracerProvider as _;
 use opentelemetry_otlp::{HasExportConfig, WithExportConfig};
 use std::time::Duration;
-use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::format::{FmtSpan, JsonFields};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::registry::Registry;
 use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
 
-fn init_tracer() -> Result<sdktrace::Tracer> {
-    let mut exporter = opentelemetry_otlp::new_exporter().tonic().with_env();
-
-    println!(
-        "Using opentelemetry endpoint {}",
-        exporter.export_config().endpoint
-    );
-
+fn resource() -> Resource {
     // overwrite the service name because k8s service name does not always matches what we want
     std::env::set_var("OTEL_SERVICE_NAME", env!("CARGO_PKG_NAME"));
 
@@ -32,32 +30,91 @@ fn init_tracer() -> Result<sdktrace::Tracer> {
 
     println!("Using opentelemetry resources {:?}", resource);
 
+    resource
+}
+
+fn init_otlp_tracer() -> Result<sdktrace::Tracer> {
+    let mut exporter = opentelemetry_otlp::new_exporter().tonic().with_env();
+
+    println!(
+        "Using opentelemetry endpoint {}",
+        exporter.export_config().endpoint
+    );
+
     Ok(opentelemetry_otlp::new_pipeline()
         .tracing()
         .with_exporter(exporter)
-        .with_trace_config(sdktrace::config().with_resource(resource))
+        .with_trace_config(sdktrace::config().with_resource(resource()))
         .install_batch(opentelemetry::runtime::Tokio)?)
 }
 
-pub fn init_tracing() -> Result<()> {
-    let tracer = init_tracer().context("Setting up the opentelemetry exporter")?;
+fn init_stdout_json_tracer() -> sdktrace::Tracer {
+    let provider = sdktrace::TracerProvider::builder()
+        .with_batch_exporter(StdoutJsonExporter, opentelemetry::runtime::Tokio)
+        .with_config(sdktrace::config().with_resource(resource()))
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    opentelemetry::global::set_tracer_provider(provider);
+
+    tracer
+}
+
+/// Sets up the tracer for the configured exporter, `None` if the spans should not be exported
+fn init_tracer(config: &config::Tracing) -> Result<Option<sdktrace::Tracer>> {
+    let exporter = match config::TraceExporter::from_env()? {
+        Some(exporter) => exporter,
+        None => config.exporter,
+    };
+    println!("Using {:?} trace exporter", exporter);
+
+    Ok(match exporter {
+        TraceExporter::Otlp => {
+            Some(init_otlp_tracer().context("Setting up the opentelemetry exporter")?)
+        }
+        TraceExporter::StdoutJson => Some(init_stdout_json_tracer()),
+        TraceExporter::None => None,
+    })
+}
+
+pub fn init_tracing(config: &config::Tracing) -> Result<()> {
+    let tracer = init_tracer(config)?;
 
     let default = concat!(env!("CARGO_PKG_NAME"), "=trace")
         .parse()
         .expect("hard-coded default directive should be valid");
 
+    let (compact_layer, json_layer) = match config.log_format {
+        LogFormat::Compact => (
+            Some(
+                tracing_subscriber::fmt::Layer::new()
+                    .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+                    .event_format(tracing_subscriber::fmt::format::Format::default().compact()),
+            ),
+            None,
+        ),
+        LogFormat::Json => (
+            None,
+            Some(
+                OtelIdsLayer.and_then(
+                    tracing_subscriber::fmt::Layer::new()
+                        .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+                        .fmt_fields(JsonFields::new())
+                        .event_format(JsonLogFormat),
+                ),
+            ),
+        ),
+    };
+
     Registry::default()
         .with(
             tracing_subscriber::EnvFilter::builder()
                 .with_default_directive(default)
                 .from_env_lossy(),
         )
-        .with(
-            tracing_subscriber::fmt::Layer::new()
-                .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-                .event_format(tracing_subscriber::fmt::format::Format::default().compact()),
-        )
-        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        // the otel layer goes first, so that the json logs can get the trace id even for the span creation events
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .with(compact_layer)
+        .with(json_layer)
         .init();
 
     Ok(())
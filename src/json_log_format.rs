@@ -0,0 +1,147 @@
+use chrono::{SecondsFormat, Utc};
+use opentelemetry::trace::{SpanId, TraceContextExt, TraceId};
+use serde_json::{Map, Value};
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Subscriber};
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::fmt::format::{JsonFields, Writer};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormattedFields};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Formats the events as JSON lines, with the fields of all the enclosing spans flattened into the line.
+///
+/// The inner span fields take precedence over the outer ones, and the event fields over all of them.
+/// When the spans are exported, the line also contains the ids of the current otel trace and span.
+///
+/// Must be used together with [`JsonFields`], as the span fields are read back from their JSON form,
+/// and with [`OtelIdsLayer`] to get the otel ids.
+pub struct JsonLogFormat;
+
+/// Collects the event fields into a JSON object
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+}
+
+/// Ids of the otel trace and span a tracing span belongs to
+#[derive(Clone, Copy)]
+struct OtelIds {
+    trace_id: TraceId,
+    span_id: SpanId,
+}
+
+impl OtelIds {
+    fn from_data(data: &OtelData) -> Option<Self> {
+        // only the root spans have the trace id in the builder, the others inherit it from the parent
+        let trace_id = data.builder.trace_id.or_else(|| {
+            let parent = data.parent_cx.span();
+            let parent = parent.span_context();
+            parent.is_valid().then(|| parent.trace_id())
+        })?;
+        let span_id = data.builder.span_id?;
+
+        Some(Self { trace_id, span_id })
+    }
+}
+
+/// Copies the otel ids into the span extensions, as [`OtelData`] is gone by the time the span close event is logged.
+///
+/// Must be placed after the otel layer.
+pub struct OtelIdsLayer;
+
+impl<S> Layer<S> for OtelIdsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        let ids = span
+            .extensions()
+            .get::<OtelData>()
+            .and_then(OtelIds::from_data);
+        if let Some(ids) = ids {
+            span.extensions_mut().insert(ids);
+        }
+    }
+}
+
+impl<S> FormatEvent<S, JsonFields> for JsonLogFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+
+        let mut line = Map::new();
+        line.insert(
+            "timestamp".to_string(),
+            Utc::now()
+                .to_rfc3339_opts(SecondsFormat::Micros, true)
+                .into(),
+        );
+        line.insert("level".to_string(), metadata.level().as_str().into());
+        line.insert("target".to_string(), metadata.target().into());
+
+        if let Some(scope) = ctx.event_scope() {
+            let mut current = None;
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                if let Some(fields) = extensions.get::<FormattedFields<JsonFields>>() {
+                    if let Ok(Value::Object(fields)) = serde_json::from_str(fields) {
+                        line.extend(fields);
+                    }
+                }
+                if let Some(ids) = extensions.get::<OtelIds>() {
+                    line.insert("trace_id".to_string(), ids.trace_id.to_string().into());
+                    line.insert("span_id".to_string(), ids.span_id.to_string().into());
+                }
+                current = Some(span.name());
+            }
+
+            if let Some(name) = current {
+                line.insert("span".to_string(), name.into());
+            }
+        }
+
+        event.record(&mut JsonVisitor(&mut line));
+
+        writeln!(
+            writer,
+            "{}",
+            serde_json::to_string(&line).map_err(|_| fmt::Error)?
+        )
+    }
+}
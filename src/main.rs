@@ -1,13 +1,31 @@
+mod admin;
 mod attendance;
+mod attendance_backend;
 mod config;
+mod encrypted_serializer;
+#[cfg(test)]
+mod fake_moodle;
+#[cfg(test)]
+mod fake_telegram;
 mod init_tracing;
+mod json_log_format;
+mod metrics;
 mod moodle;
+mod moodle_cache;
 mod moodle_extender;
+mod moodle_scheduler;
+mod moodle_scraper;
+mod reqwest_retry;
 mod reqwest_span_backend;
 mod router;
+mod secret;
+mod stdout_exporter;
 mod storage;
 mod teloxide_tracing;
+mod updater;
 
+use crate::attendance_backend::AttendanceBackend;
+use crate::encrypted_serializer::Encrypted;
 use crate::moodle::Moodle;
 use crate::moodle_extender::MoodleExtender;
 use anyhow::{Context, Result};
@@ -15,11 +33,12 @@ use dptree::deps;
 use router::{schema, MyStorage};
 use std::sync::Arc;
 use std::time::Duration;
+use teloxide::adaptors::throttle::Limits;
 use teloxide::adaptors::{DefaultParseMode, Throttle};
 use teloxide::dispatching::dialogue::serializer::Json;
 use teloxide::prelude::*;
 use teloxide::types::ParseMode;
-use teloxide::update_listeners::Polling;
+use teloxide::update_listeners::{webhooks, Polling};
 use teloxide_tracing::Trace;
 use tracing::info;
 
@@ -33,29 +52,57 @@ fn make_bot() -> Result<Bot> {
     Ok(Bot::new(token.trim()))
 }
 
+/// Wraps the bot into the adaptors the handlers expect
+fn adapt_bot(bot: Bot, limits: Limits, redaction: config::RedactionPolicy) -> MyBot {
+    Trace::new(
+        bot.parse_mode(ParseMode::Html).throttle(limits),
+        match redaction {
+            config::RedactionPolicy::Strict => teloxide_tracing::Settings::TRACE_REQUESTS,
+            // the responses are never logged verbosely: the updates contain the session cookies users send
+            config::RedactionPolicy::RevealPasswords => {
+                teloxide_tracing::Settings::TRACE_REQUESTS_VERBOSE
+            }
+        },
+    )
+}
+
+fn make_storage_serializer() -> Result<Encrypted<Json>> {
+    let key_file = std::env::var("STORAGE_KEY_FILE").context(
+        "STORAGE_KEY_FILE is not set (should contain path to file with the hex-encoded dialogue encryption key)",
+    )?;
+    let key = std::fs::read_to_string(key_file).context("Reading storage key file")?;
+    let key = encrypted_serializer::parse_key(&key).context("Parsing storage key")?;
+
+    Ok(Encrypted::new(Json, &key))
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    init_tracing::init_tracing().context("Setting up the opentelemetry exporter")?;
-    info!("Starting historia bot...");
-
     let config = config::Config::read()?;
+    secret::set_policy(config.tracing.redaction);
 
-    let bot: MyBot = Trace::new(
-        make_bot()?
-            .parse_mode(ParseMode::Html)
-            .throttle(Default::default()),
-        teloxide_tracing::Settings::all(),
-    );
+    init_tracing::init_tracing(&config.tracing).context("Setting up tracing")?;
+    info!("Starting historia bot...");
 
-    let listener = Polling::builder(bot.clone())
-        .timeout(Duration::from_secs(10))
-        .delete_webhook()
-        .await
-        .build();
+    let bot = adapt_bot(make_bot()?, Limits::default(), config.tracing.redaction);
 
-    let storage = MyStorage::open(&config.database, Json)
+    let storage = MyStorage::open(&config.database, make_storage_serializer()?)
         .await
         .context("Opening storage")?;
+    let migrated = storage
+        .migrate_legacy_registrations()
+        .await
+        .context("Moving registrations into the users table")?;
+    if migrated > 0 {
+        info!("Moved {} registrations into the users table", migrated);
+    }
+    let encrypted = storage
+        .encrypt_plaintext_dialogues()
+        .await
+        .context("Encrypting plaintext dialogues")?;
+    if encrypted > 0 {
+        info!("Encrypted {} dialogues stored in plaintext", encrypted);
+    }
 
     let moodle_extender = MoodleExtender::new(&config.moodle_extender).await?;
 
@@ -65,20 +112,76 @@ async fn main() -> Result<()> {
             .context("Opening moodle accessor")?,
     );
 
-    Dispatcher::builder(bot, schema(&config.bot))
+    if let Some(admin) = config.admin {
+        tokio::spawn(admin::run(admin, storage.clone(), moodle.clone()));
+    }
+
+    let backend: Arc<dyn AttendanceBackend> = moodle.clone();
+
+    tokio::spawn(updater::run(
+        config.updater,
+        bot.clone(),
+        storage.clone(),
+        backend.clone(),
+    ));
+
+    let config_bot = Arc::new(config.bot);
+
+    let mut dispatcher = Dispatcher::builder(bot.clone(), schema(&config_bot))
         .dependencies(deps![
-            Arc::new(config.bot),
+            config_bot.clone(),
             Arc::new(config.moodle),
             storage,
-            moodle
+            backend
         ])
         .enable_ctrlc_handler()
-        .build()
-        .dispatch_with_listener(
-            listener,
-            LoggingErrorHandler::with_custom_text("An error from the update listener"),
-        )
-        .await;
+        .build();
+    let error_handler = LoggingErrorHandler::with_custom_text("An error from the update listener");
+
+    match &config_bot.listener {
+        config::Listener::Polling => {
+            let listener = Polling::builder(bot)
+                .timeout(Duration::from_secs(10))
+                .delete_webhook()
+                .await
+                .build();
+
+            dispatcher
+                .dispatch_with_listener(listener, error_handler)
+                .await;
+        }
+        config::Listener::Webhook(webhook) => {
+            let secret_token = std::fs::read_to_string(&webhook.secret_token_file)
+                .context("Reading webhook secret token file")?;
+            let secret_token = secret_token.trim();
+            // teloxide panics on an invalid token, so check it ourselves to report a proper error
+            anyhow::ensure!(
+                (1..=256).contains(&secret_token.len())
+                    && secret_token
+                        .bytes()
+                        .all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-'),
+                "The webhook secret token in {} must be 1-256 characters of A-Z, a-z, 0-9, _ and -",
+                webhook.secret_token_file
+            );
+            let options = webhooks::Options::new(webhook.listen_address, webhook.url.clone())
+                .secret_token(secret_token.to_string());
+
+            info!(
+                "Listening for webhook updates on {} (public URL {})",
+                webhook.listen_address, webhook.url
+            );
+            let listener = webhooks::axum(bot, options)
+                .await
+                .context("Setting up the webhook")?;
+
+            dispatcher
+                .dispatch_with_listener(listener, error_handler)
+                .await;
+        }
+    }
+
+    // flush the spans still waiting in the batch exporter
+    opentelemetry::global::shutdown_tracer_provider();
 
     Ok(())
 }
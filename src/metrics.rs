@@ -0,0 +1,91 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+/// Users we tried to mark after an attendance password was posted
+pub static MARKS_ATTEMPTED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "historia_marks_attempted_total",
+        "Number of users the bot tried to mark the attendance for"
+    )
+    .unwrap()
+});
+
+/// Recorded mark outcomes, labeled by [`crate::attendance::MarkOutcome::kind`]
+pub static MARK_OUTCOMES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "historia_mark_outcomes_total",
+        "Number of attendance mark outcomes by kind (\"marked\" is the only successful one)",
+        &["outcome"]
+    )
+    .unwrap()
+});
+
+/// Backend errors that prevented marking, labeled by [`crate::attendance_backend::BackendError::kind`]
+pub static MARK_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "historia_mark_errors_total",
+        "Number of failed attendance marks by the backend error",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+pub static SESSION_CHECKS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "historia_session_checks_total",
+        "Number of moodle session validity checks by the result",
+        &["result"]
+    )
+    .unwrap()
+});
+
+/// Moodle web service calls that failed and were replaced by scraping the pages,
+/// labeled by the function and [`crate::moodle::MoodleError::kind`]
+pub static AJAX_FALLBACKS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "historia_moodle_ajax_fallbacks_total",
+        "Number of moodle web service calls that failed and were replaced by scraping the pages",
+        &["function", "reason"]
+    )
+    .unwrap()
+});
+
+pub static MOODLE_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "historia_moodle_request_duration_seconds",
+        "Latency of the requests to moodle",
+        &["method", "path", "status"]
+    )
+    .unwrap()
+});
+
+pub static RATE_LIMIT_WAIT: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "historia_moodle_rate_limit_wait_seconds",
+        "Time the requests to moodle spent waiting for the rate limiter",
+        &["priority"],
+        vec![0.0, 0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0]
+    )
+    .unwrap()
+});
+
+/// Updated when the metrics are scraped
+pub static REGISTERED_USERS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "historia_registered_users",
+        "Number of users with a registered moodle session"
+    )
+    .unwrap()
+});
+
+/// Renders all the registered metrics in the prometheus text format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("Encoding metrics into a Vec can't fail");
+    String::from_utf8(buffer).expect("Prometheus text format is utf-8")
+}
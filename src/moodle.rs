@@ -1,62 +1,42 @@
 use crate::attendance::Attendance;
+use crate::attendance_backend::BackendUser;
 use crate::config;
+use crate::metrics;
 use crate::moodle_extender::MoodleExtender;
+use crate::moodle_scheduler::{Priority, Scheduler};
+use crate::moodle_scraper;
+use crate::reqwest_retry::{Idempotent, RetryMiddleware};
 use crate::reqwest_span_backend::MoodleSpanBackend;
-use anyhow::{anyhow, bail, Context, Result};
+use crate::secret::{Password, Secret};
+use anyhow::Context;
 use chrono::{Datelike, NaiveDate};
-use email_address::EmailAddress;
-use governor::clock::DefaultClock;
-use governor::state::{InMemoryState, NotKeyed};
-use governor::{Quota, RateLimiter};
-use once_cell::sync::Lazy;
-use regex::Regex;
+use governor::Quota;
 use reqwest::header::{HeaderValue, COOKIE, LOCATION};
 use reqwest::redirect::Policy;
+use reqwest::{Response, StatusCode};
 use reqwest_tracing::TracingMiddleware;
-use scraper::{ElementRef, Html, Selector};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::fmt::{Debug, Display};
+use std::collections::HashMap;
+use std::fmt::Debug;
 use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tracing::{debug, info, instrument, trace};
+use thiserror::Error;
+use tokio::sync::OnceCell;
+use tracing::{debug, info, instrument, warn};
 use url::Url;
 
-static EMAIL_EXTRACT_REGEX: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r#"<dt>(?:Email address|Адрес электронной почты)</dt><dd><a href="([^"]+)">"#)
-        .unwrap()
-});
-static SESSION_EXTRACT_REGEX: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r#""sesskey":"([^"]+)""#).unwrap());
-
-#[derive(Clone, Default, Serialize, Deserialize)]
-pub struct MoodleUser {
-    session: String,
-    email: String,
-}
-
-impl Debug for MoodleUser {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("MoodleUser")
-            // do not include session in debug output (it's a secret)
-            // .field("session", &self.session)
-            .field("email", &self.email)
-            .finish()
-    }
-}
-
-impl Display for MoodleUser {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.email)
-    }
-}
-
 pub struct Moodle {
     extender: MoodleExtender,
     reqwest: reqwest_middleware::ClientWithMiddleware,
     base_url: Url,
-    rate_limiter: RateLimiter<NotKeyed, InMemoryState, DefaultClock>,
+    scheduler: Arc<Scheduler>,
+    /// The "Present" status ids by the session id, fetched once for all users marking in the session
+    present_statuses: Mutex<HashMap<u32, Arc<OnceCell<u32>>>>,
 }
 
+/// A call to a moodle web service function through `/lib/ajax/service.php`
 #[derive(Serialize)]
 struct AjaxPayload<T> {
     index: u32,
@@ -64,33 +44,242 @@ struct AjaxPayload<T> {
     args: T,
 }
 
+#[derive(Debug, Deserialize)]
+struct AjaxException {
+    // the whole request failing has the message in the `error` field
+    #[serde(alias = "error")]
+    message: String,
+    errorcode: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AjaxResult<T> {
+    error: bool,
+    data: Option<T>,
+    exception: Option<AjaxException>,
+}
+
+/// Moodle responds with a result for each call, or with a single exception if the whole request failed
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum AjaxResponse<T> {
+    Results(Vec<AjaxResult<T>>),
+    Failed(AjaxException),
+}
+
+/// An entry returned by `core_user_get_users_by_field`
+#[derive(Debug, Deserialize)]
+struct AjaxUser {
+    /// Missing if the user can't see the email (it is hidden by the site settings)
+    email: Option<String>,
+}
+
+/// The part of `mod_attendance_get_session` response we need
+#[derive(Debug, Deserialize)]
+struct AjaxSession {
+    statuses: Vec<AjaxStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AjaxStatus {
+    id: u32,
+    description: String,
+}
+
 #[derive(Debug)]
 pub enum SessionProbeResult {
     Invalid,
-    Valid { email: String, csrf_session: String },
+    Valid {
+        email: String,
+        csrf_session: Secret<String>,
+    },
 }
 
+/// How long the requests not needed for marking give way to the marks during a password event
+const MAX_NORMAL_DELAY: Duration = Duration::from_secs(10);
+
+/// Name of the attendance status we mark the users with
+pub const PRESENT_STATUS: &str = "Present";
+
+/// How many sessions to remember the "Present" status ids for. Only the sessions of the current event are needed.
+const MAX_CACHED_STATUSES: usize = 64;
+
+/// A row from the user's attendance report
 #[derive(Debug)]
-pub struct AttendanceSession {
-    pub id: u32,
+pub struct AttendanceReportEntry {
     pub date: NaiveDate,
+    /// Id of the session, present only if the user can submit the attendance for it right now
+    pub session_id: Option<u32>,
+    /// The status moodle recorded (like "Present"), if the attendance was taken
+    pub status: Option<String>,
+    /// Points as displayed by moodle (like "2 / 2")
+    pub points: Option<String>,
 }
 
-impl AttendanceSession {
+impl AttendanceReportEntry {
     pub fn matches(&self, attendance: &Attendance) -> bool {
-        self.date.day() == attendance.day as u32 && self.date.month() == attendance.month as u32
+        date_matches(self.date, attendance)
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.status.as_deref() == Some(PRESENT_STATUS)
+    }
+}
+
+/// An error returned from [`Moodle`] methods.
+#[derive(Debug, Error)]
+pub enum MoodleError {
+    #[error("moodle session has expired")]
+    SessionExpired,
+    #[error("moodle is rate limiting us")]
+    RateLimited,
+    #[error("moodle responded with status {0}")]
+    Status(StatusCode),
+    #[error("moodle redirected to an unexpected location: {0}")]
+    UnexpectedRedirect(String),
+    #[error("moodle page layout has changed: could not find {selector}")]
+    LayoutChanged { selector: &'static str },
+    #[error("unexpected moodle response: {0}")]
+    InvalidResponse(String),
+    #[error("network error: {0}")]
+    Network(#[from] reqwest_middleware::Error),
+    #[error("moodle extender error: {0:#}")]
+    Extender(anyhow::Error),
+    #[error("invalid url: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("incorrect password")]
+    WrongPassword,
+    #[error("the session is closed")]
+    SessionClosed,
+    #[error("could not find the \"{PRESENT_STATUS}\" status")]
+    NoPresentStatus,
+    /// Moodle showed an error we could not classify
+    #[error("moodle rejected the mark: {0}")]
+    Rejected(String),
+    #[error("moodle web service {function} failed with {errorcode}: {message}")]
+    WebService {
+        function: &'static str,
+        errorcode: String,
+        message: String,
+    },
+}
+
+impl From<reqwest::Error> for MoodleError {
+    fn from(e: reqwest::Error) -> Self {
+        MoodleError::Network(e.into())
+    }
+}
+
+impl MoodleError {
+    /// Short name of the error variant, used as a metric label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MoodleError::SessionExpired => "session_expired",
+            MoodleError::RateLimited => "rate_limited",
+            MoodleError::Status(_) => "status",
+            MoodleError::UnexpectedRedirect(_) => "unexpected_redirect",
+            MoodleError::LayoutChanged { .. } => "layout_changed",
+            MoodleError::InvalidResponse(_) => "invalid_response",
+            MoodleError::Network(_) => "network",
+            MoodleError::Extender(_) => "extender",
+            MoodleError::Url(_) => "url",
+            MoodleError::WrongPassword => "wrong_password",
+            MoodleError::SessionClosed => "session_closed",
+            MoodleError::NoPresentStatus => "no_present_status",
+            MoodleError::Rejected(_) => "rejected",
+            MoodleError::WebService { .. } => "web_service",
+        }
     }
+
+    /// Classifies the error message moodle shows when marking fails
+    fn from_mark_message(message: String) -> Self {
+        let lowercase = message.to_lowercase();
+        if lowercase.contains("password") || lowercase.contains("пароль") {
+            MoodleError::WrongPassword
+        } else if ["closed", "already", "not allowed", "закрыт", "уже"]
+            .iter()
+            .any(|v| lowercase.contains(v))
+        {
+            MoodleError::SessionClosed
+        } else {
+            MoodleError::Rejected(message)
+        }
+    }
+}
+
+pub type Result<T, E = MoodleError> = std::result::Result<T, E>;
+
+fn date_matches(date: NaiveDate, attendance: &Attendance) -> bool {
+    date.day() == attendance.day as u32 && date.month() == attendance.month as u32
+}
+
+fn session_cookie(user: &BackendUser) -> Result<HeaderValue> {
+    HeaderValue::from_str(&format!("MoodleSession={}", user.session().expose())).map_err(|_| {
+        MoodleError::InvalidResponse("session cookie contains invalid characters".to_string())
+    })
+}
+
+/// Drops the URL from the request errors, where it would show the sesskey
+fn without_url(e: reqwest_middleware::Error) -> MoodleError {
+    MoodleError::Network(match e {
+        reqwest_middleware::Error::Reqwest(e) => {
+            reqwest_middleware::Error::Reqwest(e.without_url())
+        }
+        reqwest_middleware::Error::Middleware(e) => match e.downcast::<reqwest::Error>() {
+            Ok(e) => reqwest_middleware::Error::Reqwest(e.without_url()),
+            Err(e) => reqwest_middleware::Error::Middleware(e),
+        },
+    })
+}
+
+/// Turns the error statuses into [`MoodleError`]s
+fn check_status(resp: Response) -> Result<Response> {
+    match resp.status() {
+        StatusCode::TOO_MANY_REQUESTS => Err(MoodleError::RateLimited),
+        status if status.is_client_error() || status.is_server_error() => {
+            Err(MoodleError::Status(status))
+        }
+        _ => Ok(resp),
+    }
+}
+
+/// Moodle redirects to the login page when the session is not valid anymore
+fn redirect_error(location: Url) -> MoodleError {
+    if location.path().starts_with("/login/") {
+        MoodleError::SessionExpired
+    } else {
+        MoodleError::UnexpectedRedirect(location.to_string())
+    }
+}
+
+/// Returns the redirect target of the response, if it is a redirect
+fn redirect_location(resp: &Response) -> Option<Result<Url>> {
+    if !resp.status().is_redirection() {
+        return None;
+    }
+
+    Some(
+        resp.headers()
+            .get(LOCATION)
+            .ok_or_else(|| MoodleError::InvalidResponse("missing Location header".to_string()))
+            .and_then(|v| {
+                v.to_str().map_err(|_| {
+                    MoodleError::InvalidResponse("non-ascii Location header".to_string())
+                })
+            })
+            .and_then(|v| Ok(resp.url().join(v)?)),
+    )
 }
 
 impl Moodle {
-    pub async fn new(config: &config::Moodle, extender: MoodleExtender) -> Result<Self> {
+    pub async fn new(config: &config::Moodle, extender: MoodleExtender) -> anyhow::Result<Self> {
         let period = Duration::from_millis(1000 * 60 / config.rpm as u64);
 
         let quota = Quota::with_period(period)
             .context("Period is invalid")?
             .allow_burst(NonZeroU32::new(config.max_burst).context("Burst is invalid")?);
 
-        let rate_limiter = governor::RateLimiter::direct(quota);
+        let scheduler = Arc::new(Scheduler::new(quota, MAX_NORMAL_DELAY));
 
         Ok(Moodle {
             extender,
@@ -100,253 +289,354 @@ impl Moodle {
                     .redirect(Policy::none())
                     .build()?,
             )
+            .with(RetryMiddleware::new(&config.retry).with_scheduler(scheduler.clone()))
             .with(TracingMiddleware::<MoodleSpanBackend>::new())
             .build(),
             base_url: config.base_url.clone(),
-            rate_limiter,
+            scheduler,
+            present_statuses: Default::default(),
         })
     }
 
     #[instrument(skip_all, err, ret)]
-    pub async fn make_user(&self, session: String) -> Result<Option<MoodleUser>> {
+    pub async fn make_user(&self, session: Secret<String>) -> Result<Option<BackendUser>> {
         let email = self
             .extender
             .extend_session(&session)
             .await
-            .context("Extending session")?;
+            .map_err(MoodleError::Extender)?;
 
-        Ok(email.map(|email| MoodleUser { session, email }))
+        Ok(email.map(|email| BackendUser::new(session, email)))
     }
 
+    /// Asks the extender to keep the user's session alive. Returns `None` if the session is invalid.
     #[instrument(skip_all, err, ret, fields(moodle.user = %user))]
-    pub async fn check_user(&self, user: &MoodleUser) -> Result<SessionProbeResult> {
-        self.rate_limiter.until_ready().await;
+    pub async fn extend_session(&self, user: &BackendUser) -> Result<Option<String>> {
+        self.extender
+            .extend_session(user.session())
+            .await
+            .map_err(MoodleError::Extender)
+    }
 
-        let url = self.base_url.join("/user/profile.php")?;
+    /// Checks that moodle responds, without using any user session
+    #[instrument(skip_all, err)]
+    pub async fn ping(&self) -> Result<()> {
+        self.scheduler.until_ready(Priority::Normal).await;
+
+        let resp = self
+            .reqwest
+            .get(self.base_url.join("/login/index.php")?)
+            .send()
+            .await?;
+        check_status(resp)?;
+
+        Ok(())
+    }
+
+    /// Fetches a page on behalf of the user, treating redirects to the login page as an expired session
+    async fn get_page(&self, user: &BackendUser, url: Url, priority: Priority) -> Result<String> {
+        self.scheduler.until_ready(priority).await;
 
         let resp = self
             .reqwest
             .get(url)
-            .header(
-                COOKIE,
-                HeaderValue::from_str(&format!("MoodleSession={}", user.session))?,
-            )
+            .header(COOKIE, session_cookie(user)?)
+            // the retries wait for the rate limiter with the same priority
+            .with_extension(priority)
             .send()
             .await?;
-        if resp.status().is_redirection() {
+        let resp = check_status(resp)?;
+
+        if let Some(location) = redirect_location(&resp) {
+            let location = location?;
             info!(
-                "Moodle redirected using status {} to {:?}; sessions is likely invalid",
+                "Moodle redirected using status {} to {}",
                 resp.status(),
-                resp.headers().get(LOCATION)
+                location
             );
-            return Ok(SessionProbeResult::Invalid);
+            return Err(redirect_error(location));
         }
 
-        let body = resp.text().await?;
-        let encoded_email = EMAIL_EXTRACT_REGEX
-            .captures(&body)
-            .context("Could not find email on the profile page")?
-            .get(1)
-            .unwrap()
-            .as_str();
-
-        let email = urlencoding::decode(encoded_email).context("Decoding email")?;
-        let email = html_escape::decode_html_entities(&email);
-        let email = email
-            .strip_prefix("mailto:")
-            .context("Stripping mailto prefix")?;
-
-        if !EmailAddress::is_valid(email) {
-            return Err(anyhow!(
-                "Extracted email address {}, but it seems to be invalid",
-                email
-            ));
+        Ok(resp.text().await?)
+    }
+
+    /// Calls a moodle web service function the same way moodle's own javascript does, authenticating with
+    /// the session cookie and the CSRF token.
+    ///
+    /// Only the functions that are available through AJAX can be called this way.
+    async fn call_ajax<A: Serialize, R: DeserializeOwned>(
+        &self,
+        user: &BackendUser,
+        csrf_session: &Secret<String>,
+        function: &'static str,
+        args: A,
+        priority: Priority,
+    ) -> Result<R> {
+        self.scheduler.until_ready(priority).await;
+
+        let mut url = self.base_url.join("/lib/ajax/service.php")?;
+        url.query_pairs_mut()
+            .append_pair("sesskey", csrf_session.expose())
+            .append_pair("info", function);
+
+        let resp = self
+            .reqwest
+            .post(url)
+            .header(COOKIE, session_cookie(user)?)
+            .json(&[AjaxPayload {
+                index: 0,
+                methodname: function.to_string(),
+                args,
+            }])
+            // we only call the functions that read the data
+            .with_extension(Idempotent)
+            .with_extension(priority)
+            .send()
+            .await
+            .map_err(without_url)?;
+        let resp = check_status(resp)?;
+
+        if let Some(location) = redirect_location(&resp) {
+            return Err(redirect_error(location?));
         }
 
-        info!("Session seems to be valid; email = {}", email);
+        let body = resp.text().await.map_err(|e| without_url(e.into()))?;
+        let response: AjaxResponse<R> = serde_json::from_str(&body).map_err(|e| {
+            MoodleError::InvalidResponse(format!("could not parse {} response: {}", function, e))
+        })?;
 
-        let sesskey = SESSION_EXTRACT_REGEX
-            .captures(&body)
-            .context("Could not find sesskey on the profile page")?
-            .get(1)
-            .unwrap()
-            .as_str();
+        let result = match response {
+            AjaxResponse::Results(results) => results.into_iter().next().ok_or_else(|| {
+                MoodleError::InvalidResponse(format!("{} response has no results", function))
+            })?,
+            AjaxResponse::Failed(exception) => AjaxResult {
+                error: true,
+                data: None,
+                exception: Some(exception),
+            },
+        };
+
+        match result {
+            AjaxResult {
+                error: false,
+                data: Some(data),
+                ..
+            } => Ok(data),
+            AjaxResult {
+                exception: Some(exception),
+                ..
+            } => Err(match exception.errorcode.as_str() {
+                // the sesskey changes only with the session
+                "servicerequireslogin" | "requireloginerror" | "invalidsesskey" => {
+                    MoodleError::SessionExpired
+                }
+                _ => MoodleError::WebService {
+                    function,
+                    errorcode: exception.errorcode,
+                    message: exception.message,
+                },
+            }),
+            _ => Err(MoodleError::InvalidResponse(format!(
+                "{} response has neither data nor exception",
+                function
+            ))),
+        }
+    }
+
+    /// Falls back to scraping the pages when the web service call did not work out
+    fn ajax_fallback<T>(function: &'static str, result: Result<T>) -> Result<Option<T>> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            // the page would not work either
+            Err(MoodleError::SessionExpired) => Err(MoodleError::SessionExpired),
+            Err(e) => {
+                warn!(
+                    "Web service call {} failed, scraping the page: {}",
+                    function, e
+                );
+                metrics::AJAX_FALLBACKS
+                    .with_label_values(&[function, e.kind()])
+                    .inc();
+                Ok(None)
+            }
+        }
+    }
+
+    #[instrument(skip_all, err, ret, fields(moodle.user = %user))]
+    pub async fn check_user(&self, user: &BackendUser) -> Result<SessionProbeResult> {
+        let result = self.probe_session(user).await;
+        let label = match &result {
+            Ok(SessionProbeResult::Valid { .. }) => "valid",
+            Ok(SessionProbeResult::Invalid) => "invalid",
+            Err(_) => "error",
+        };
+        metrics::SESSION_CHECKS.with_label_values(&[label]).inc();
+
+        result
+    }
+
+    async fn probe_session(&self, user: &BackendUser) -> Result<SessionProbeResult> {
+        let url = self.base_url.join("/user/profile.php")?;
+
+        let body = match self.get_page(user, url, Priority::Normal).await {
+            Ok(body) => body,
+            Err(MoodleError::SessionExpired | MoodleError::UnexpectedRedirect(_)) => {
+                info!("Sessions is likely invalid");
+                return Ok(SessionProbeResult::Invalid);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let sesskey = moodle_scraper::parse_sesskey(&body)?;
+        let email = match moodle_scraper::parse_user_id(&body) {
+            Some(user_id) => {
+                let email = self.get_user_email(user, &sesskey, user_id).await;
+                match Self::ajax_fallback("core_user_get_users_by_field", email) {
+                    Ok(email) => email,
+                    Err(MoodleError::SessionExpired) => {
+                        info!("Sessions is likely invalid");
+                        return Ok(SessionProbeResult::Invalid);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            None => {
+                warn!("Could not find the user id on the profile page, scraping the email");
+                None
+            }
+        };
+        let email = match email {
+            Some(email) => email,
+            None => moodle_scraper::parse_email(&body)?,
+        };
+        info!("Session seems to be valid; email = {}", email);
 
         Ok(SessionProbeResult::Valid {
-            email: email.to_string(),
-            csrf_session: sesskey.to_string(),
+            email,
+            csrf_session: sesskey,
         })
     }
 
-    #[instrument(skip_all, err, fields(moodle.activity_id = %activity_id, moodle.user = %user))]
-    pub async fn get_attendance_sessions(
+    async fn get_user_email(
         &self,
-        activity_id: u32,
-        user: &MoodleUser,
-    ) -> Result<Vec<AttendanceSession>> {
-        self.rate_limiter.until_ready().await;
-
-        let url = self.make_attendance_url(activity_id)?;
+        user: &BackendUser,
+        csrf_session: &Secret<String>,
+        user_id: u32,
+    ) -> Result<String> {
+        #[derive(Serialize)]
+        struct Args {
+            field: &'static str,
+            values: [u32; 1],
+        }
 
-        let resp = self
-            .reqwest
-            .get(url)
-            .header(
-                COOKIE,
-                HeaderValue::from_str(&format!("MoodleSession={}", user.session))?,
+        let users: Vec<AjaxUser> = self
+            .call_ajax(
+                user,
+                csrf_session,
+                "core_user_get_users_by_field",
+                Args {
+                    field: "id",
+                    values: [user_id],
+                },
+                Priority::Normal,
             )
-            .send()
-            .await?
-            .error_for_status()?;
-
-        static TABLE_SELECTOR: Lazy<Selector> = Lazy::new(|| {
-            Selector::parse("table.generaltable.attwidth.boxaligncenter > tbody").unwrap()
-        });
-        static DATE_SELECTOR: Lazy<Selector> =
-            Lazy::new(|| Selector::parse("td:nth-of-type(1)").unwrap());
-        static LINK_SELECTOR: Lazy<Selector> =
-            Lazy::new(|| Selector::parse("td:nth-of-type(3) > a").unwrap());
-        static DATE_FORMATS: [&str; 2] = [
-            // 23.01.23 (Mon)
-            "%d.%m.%y (%a)",
-            // Mon 23 Jan 2023
-            "%a %d %b %Y",
-        ];
-
-        let resp = Html::parse_document(&resp.text().await?);
-        let table = resp
-            .select(&TABLE_SELECTOR)
+            .await?;
+
+        users
+            .into_iter()
             .next()
-            .context("Could not find attendance table")?;
-
-        let mut result = Vec::new();
-        for session in table.children() {
-            // skip non-element nodes
-            let Some(session) = ElementRef::wrap(session) else { continue };
-
-            trace!("Session element: {:?}", session.value());
-
-            let date = session
-                .select(&DATE_SELECTOR)
-                .next()
-                .ok_or_else(|| anyhow!("Could not find date in session node"))
-                .and_then(|v| {
-                    v.text()
-                        .next()
-                        .ok_or_else(|| anyhow!("Could not find date in session node"))
-                })?
-                .trim();
-            let date = DATE_FORMATS
-                .into_iter()
-                .map(|fmt| NaiveDate::parse_from_str(&date, fmt).context("Parsing date"))
-                .fold(Err(anyhow!("")), |acc, res| acc.or(res))?;
-
-            let Some(link) = session
-                .select(&LINK_SELECTOR)
-                .next()
-            else {
-                debug!("Skipping session node, as it's missing a link. Probably a closed session or smth");
-                continue
-            };
-
-            let link = link
-                .value()
-                .attr("href")
-                .context("Could not find link href")?;
-            let link = Url::parse(link).context("Could not parse link")?;
-            let id = link
-                .query_pairs()
-                .find(|(k, _)| k == "sessid")
-                .map(|(_, v)| v)
-                .context("Could not find sessid in link")?
-                .parse::<u32>()
-                .context("Parsing id")?;
-
-            result.push(AttendanceSession { id, date });
-        }
+            .ok_or_else(|| MoodleError::InvalidResponse(format!("user {} not found", user_id)))?
+            .email
+            .ok_or_else(|| MoodleError::InvalidResponse("the email is hidden".to_string()))
+    }
+
+    /// Scrapes the user's attendance report, listing all sessions of the activity along with the recorded statuses.
+    ///
+    /// The `mod_attendance_*` web service functions don't tell whether the student can mark themselves
+    /// in a session, so the report is always scraped.
+    #[instrument(skip_all, err, fields(moodle.activity_id = %activity_id, moodle.user = %user))]
+    pub async fn get_attendance_report(
+        &self,
+        activity_id: u32,
+        user: &BackendUser,
+    ) -> Result<Vec<AttendanceReportEntry>> {
+        let url = self.make_attendance_url(activity_id)?;
+        let body = self.get_page(user, url, Priority::Normal).await?;
 
-        Ok(result)
+        moodle_scraper::parse_attendance_report(&body)
     }
 
+    /// Gets the statuses of the session through the web service, scraping the submission form if it fails
     #[instrument(skip_all, err, fields(moodle.session_id = %session_id, moodle.user = %user))]
     pub async fn get_session_statuses(
         &self,
-        user: &MoodleUser,
+        user: &BackendUser,
+        csrf_session: &Secret<String>,
         session_id: u32,
     ) -> Result<Vec<(u32, String)>> {
-        self.rate_limiter.until_ready().await;
-
-        let url = self.base_url.join(&format!(
-            "/mod/attendance/attendance.php?sessid={}",
-            session_id
-        ))?;
+        #[derive(Serialize)]
+        struct Args {
+            sessionid: u32,
+        }
 
-        let resp = self
-            .reqwest
-            .get(url)
-            .header(
-                COOKIE,
-                HeaderValue::from_str(&format!("MoodleSession={}", user.session))?,
+        let session = self
+            .call_ajax::<_, AjaxSession>(
+                user,
+                csrf_session,
+                "mod_attendance_get_session",
+                Args {
+                    sessionid: session_id,
+                },
+                Priority::Mark,
             )
-            .send()
-            .await?
-            .error_for_status()?;
-        let resp = Html::parse_document(&resp.text().await?);
-
-        static LABELS_SELECTOR: Lazy<Selector> =
-            Lazy::new(|| Selector::parse("#fgroup_id_statusarray label").unwrap());
-        static INPUT_SELECTOR: Lazy<Selector> = Lazy::new(|| Selector::parse("input").unwrap());
-
-        let mut result = Vec::new();
-
-        for label in resp.select(&LABELS_SELECTOR) {
-            let name = label
-                .text()
-                .find(|v| !v.trim().is_empty())
-                .context("Getting label text")?
-                .trim();
-
-            let id = label
-                .select(&INPUT_SELECTOR)
-                .next()
-                .context("Getting input")?
-                .value()
-                .attr("value")
-                .context("Getting input value")?
-                .parse::<u32>()
-                .context("Parsing input value")?;
-
-            result.push((id, name.to_string()));
+            .await;
+        if let Some(session) = Self::ajax_fallback("mod_attendance_get_session", session)? {
+            return Ok(session
+                .statuses
+                .into_iter()
+                .map(|status| (status.id, status.description))
+                .collect());
         }
 
-        Ok(result)
+        let url = self.make_session_url(session_id)?;
+        // the statuses are needed to submit the mark
+        let body = self.get_page(user, url, Priority::Mark).await?;
+
+        moodle_scraper::parse_session_statuses(&body)
     }
 
-    #[instrument(skip_all, err, fields(moodle.session_id = %session_id, moodle.session_password = %password, moodle.user = %user))]
-    pub async fn mark_attendance_session(
+    /// Finds the id of the "Present" status to mark the user with
+    #[instrument(skip_all, err, ret, fields(moodle.session_id = %session_id, moodle.user = %user))]
+    pub async fn get_present_status(
         &self,
-        user: &MoodleUser,
-        csrf_session: &str,
+        user: &BackendUser,
+        csrf_session: &Secret<String>,
         session_id: u32,
-        password: &str,
-    ) -> Result<()> {
+    ) -> Result<u32> {
         let statuses = self
-            .get_session_statuses(user, session_id)
-            .await
-            .context("Getting attendance statuses")?;
+            .get_session_statuses(user, csrf_session, session_id)
+            .await?;
 
         debug!("Got statuses: {:?}", statuses);
 
         // select one with name "Present"
-        let status_id = statuses
+        statuses
             .into_iter()
-            .find(|(_, name)| name == "Present")
+            .find(|(_, name)| name == PRESENT_STATUS)
             .map(|(id, _)| id)
-            .context("Finding status id")?;
-
-        debug!("Selected status: {}", status_id);
+            .ok_or(MoodleError::NoPresentStatus)
+    }
 
-        self.rate_limiter.until_ready().await;
+    #[instrument(skip_all, err, fields(moodle.session_id = %session_id, moodle.status_id = %status_id, moodle.attendance_password = %password, moodle.user = %user))]
+    pub async fn mark_attendance_session(
+        &self,
+        user: &BackendUser,
+        csrf_session: &Secret<String>,
+        session_id: u32,
+        status_id: u32,
+        password: &Password,
+    ) -> Result<()> {
+        self.scheduler.until_ready(Priority::Mark).await;
 
         let url = self.base_url.join("/mod/attendance/attendance.php")?;
 
@@ -365,66 +655,502 @@ impl Moodle {
         let resp = self
             .reqwest
             .post(url)
-            .header(
-                COOKIE,
-                HeaderValue::from_str(&format!("MoodleSession={}", user.session))?,
-            )
+            .header(COOKIE, session_cookie(user)?)
             .form(&Body {
-                sesskey: csrf_session,
+                sesskey: csrf_session.expose(),
                 sessid: session_id,
                 _qf__mod_attendance_form_studentattendance: 1,
                 mform_isexpanded_id_session: 1,
-                studentpassword: password,
+                studentpassword: password.expose(),
                 submitbutton: "Save changes",
                 status: status_id, // magic number
             })
             .send()
-            .await?
-            .error_for_status()?;
+            .await?;
+        let resp = check_status(resp)?;
 
         let status = resp.status();
-        let location = resp.headers().get(LOCATION).cloned();
+        let location = redirect_location(&resp).transpose()?;
 
-        let _body = resp.text().await?;
+        let body = resp.text().await?;
 
-        if !status.is_redirection() {
-            bail!(
-                "Unexpected response status: {} (expected a redirect). Invalid status?",
+        let Some(location) = location else {
+            // moodle re-renders the form when it fails the validation
+            if let Some(error) = moodle_scraper::extract_page_error(&body) {
+                return Err(MoodleError::from_mark_message(error));
+            }
+            return Err(MoodleError::InvalidResponse(format!(
+                "unexpected response status: {} (expected a redirect). Invalid status?",
                 status
-            );
-        }
-
-        let location = location
-            .as_ref()
-            .ok_or_else(|| anyhow!("Missing header"))
-            .and_then(|v| v.to_str().context("Header to str"))
-            .and_then(|v| Url::parse(v).context("Parse as Url"))?;
+            )));
+        };
 
         match location.path() {
             "/mod/attendance/view.php" => Ok(()),
             "/mod/attendance/attendance.php" => {
-                // TODO: try to follow and extract the error
-                bail!("Moodle redirected to the same page, probably some error occurred")
+                // the error is shown as a notification on the page we were redirected to
+                let body = self.get_page(user, location, Priority::Mark).await?;
+                let error = moodle_scraper::extract_page_error(&body).ok_or_else(|| {
+                    MoodleError::InvalidResponse(
+                        "moodle redirected to the same page, but did not show any error"
+                            .to_string(),
+                    )
+                })?;
+
+                Err(MoodleError::from_mark_message(error))
+            }
+            path if path.starts_with("/login/") => Err(MoodleError::SessionExpired),
+            _ => Err(MoodleError::UnexpectedRedirect(location.to_string())),
+        }
+    }
+
+    /// Marks the user with the "Present" status in the session.
+    ///
+    /// The status id is (almost always) the same for all users, so it is fetched once per session. If marking
+    /// with the id fetched for another user fails, the id is fetched again for this user.
+    pub async fn mark_present(
+        &self,
+        user: &BackendUser,
+        csrf_session: &Secret<String>,
+        session_id: u32,
+        password: &Password,
+    ) -> Result<()> {
+        let cell = {
+            let mut statuses = self.present_statuses.lock().unwrap();
+            if !statuses.contains_key(&session_id) && statuses.len() >= MAX_CACHED_STATUSES {
+                statuses.clear();
             }
-            path => bail!("Unknown redirect path: {}", path),
+            statuses.entry(session_id).or_default().clone()
+        };
+
+        let mut fetched = false;
+        let &status_id = cell
+            .get_or_try_init(|| {
+                fetched = true;
+                self.get_present_status(user, csrf_session, session_id)
+            })
+            .await?;
+
+        let result = self
+            .mark_attendance_session(user, csrf_session, session_id, status_id, password)
+            .await;
+        // the wrong password is wrong with any status, and the expired session can't fetch it
+        if fetched
+            || matches!(
+                result,
+                Ok(()) | Err(MoodleError::WrongPassword | MoodleError::SessionExpired)
+            )
+        {
+            return result;
+        }
+
+        warn!(
+            "Marking with the cached status {} failed, fetching it for this user",
+            status_id
+        );
+        let own_status_id = self
+            .get_present_status(user, csrf_session, session_id)
+            .await?;
+        if own_status_id == status_id {
+            return result;
         }
+        self.mark_attendance_session(user, csrf_session, session_id, own_status_id, password)
+            .await
     }
 
     pub fn make_attendance_url(&self, activity_id: u32) -> Result<Url> {
-        self.base_url
-            .join(&format!(
-                "/mod/attendance/view.php?id={}&view=5",
-                activity_id
-            ))
-            .context("Making attendance URL")
+        Ok(self.base_url.join(&format!(
+            "/mod/attendance/view.php?id={}&view=5",
+            activity_id
+        ))?)
     }
 
     pub fn make_session_url(&self, session_id: u32) -> Result<Url> {
-        self.base_url
-            .join(&format!(
-                "/mod/attendance/attendance.php?sessid={}",
-                session_id
-            ))
-            .context("Making session URL")
+        Ok(self.base_url.join(&format!(
+            "/mod/attendance/attendance.php?sessid={}",
+            session_id
+        ))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attendance_backend::AttendanceBackend;
+    use crate::fake_moodle::{self, FakeMoodle, MarkForm};
+
+    fn user(session: &str) -> BackendUser {
+        BackendUser::new(
+            Secret::new(session.to_string()),
+            fake_moodle::EMAIL.to_string(),
+        )
+    }
+
+    fn date(day: u32, month: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2023, month, day).unwrap()
+    }
+
+    #[tokio::test]
+    async fn checks_valid_session() {
+        let fake = FakeMoodle::start().await;
+        let moodle = fake.client().await;
+
+        let SessionProbeResult::Valid {
+            email,
+            csrf_session,
+        } = moodle
+            .check_user(&user(fake_moodle::SESSION))
+            .await
+            .unwrap()
+        else {
+            panic!("expected the session to be valid");
+        };
+        assert_eq!(email, fake_moodle::EMAIL);
+        assert_eq!(csrf_session.expose(), fake_moodle::SESSKEY);
+        assert_eq!(fake.ajax_calls(), ["core_user_get_users_by_field"]);
+    }
+
+    #[tokio::test]
+    async fn checks_session_without_web_service() {
+        let fake = FakeMoodle::start().await;
+        let moodle = fake.client().await;
+        fake.disable_ajax();
+
+        let SessionProbeResult::Valid {
+            email,
+            csrf_session,
+        } = moodle
+            .check_user(&user(fake_moodle::SESSION))
+            .await
+            .unwrap()
+        else {
+            panic!("expected the session to be valid");
+        };
+        // scraped from the profile page
+        assert_eq!(email, fake_moodle::EMAIL);
+        assert_eq!(csrf_session.expose(), fake_moodle::SESSKEY);
+    }
+
+    #[tokio::test]
+    async fn checks_expired_session() {
+        let fake = FakeMoodle::start().await;
+        let moodle = fake.client().await;
+
+        assert!(matches!(
+            moodle.check_user(&user("expired")).await.unwrap(),
+            SessionProbeResult::Invalid
+        ));
+    }
+
+    #[tokio::test]
+    async fn makes_user_through_extender() {
+        let fake = FakeMoodle::start().await;
+        let moodle = fake.client().await;
+
+        let user = moodle
+            .make_user(Secret::new(fake_moodle::SESSION.to_string()))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.email(), fake_moodle::EMAIL);
+
+        assert!(moodle
+            .make_user(Secret::new("expired".to_string()))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn scrapes_attendance_report() {
+        let fake = FakeMoodle::start().await;
+        let moodle = fake.client().await;
+        let user = user(fake_moodle::SESSION);
+
+        let report = moodle
+            .get_attendance_report(fake_moodle::ACTIVITY_ID, &user)
+            .await
+            .unwrap();
+        let report = report
+            .iter()
+            .map(|e| {
+                (
+                    e.date,
+                    e.session_id,
+                    e.status.as_deref(),
+                    e.points.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            report,
+            [
+                (date(7, 2), None, Some("Present"), Some("2 / 2")),
+                (date(9, 2), None, Some("Absent"), Some("0 / 2")),
+                (
+                    date(14, 2),
+                    Some(fake_moodle::SESSION_ID),
+                    None,
+                    Some("? / 2")
+                ),
+                (date(16, 2), None, None, Some("? / 2")),
+            ]
+        );
+
+        let attendance = Attendance {
+            day: 14,
+            month: 2,
+            password: Password::new(fake_moodle::PASSWORD.to_string()),
+        };
+        let sessions = moodle
+            .list_sessions(fake_moodle::ACTIVITY_ID, &user, &attendance)
+            .await
+            .unwrap();
+        assert_eq!(sessions.len(), 1);
+        let session = sessions[0].session().unwrap();
+        assert_eq!(session.id, fake_moodle::SESSION_ID);
+        assert_eq!(session.date, date(14, 2));
+    }
+
+    #[tokio::test]
+    async fn expired_session_is_reported_when_scraping() {
+        let fake = FakeMoodle::start().await;
+        let moodle = fake.client().await;
+
+        assert!(matches!(
+            moodle
+                .get_attendance_report(fake_moodle::ACTIVITY_ID, &user("expired"))
+                .await,
+            Err(MoodleError::SessionExpired)
+        ));
+    }
+
+    fn sesskey() -> Secret<String> {
+        Secret::new(fake_moodle::SESSKEY.to_string())
+    }
+
+    async fn check_session_statuses(moodle: &Moodle) {
+        let user = user(fake_moodle::SESSION);
+
+        let statuses = moodle
+            .get_session_statuses(&user, &sesskey(), fake_moodle::SESSION_ID)
+            .await
+            .unwrap();
+        assert_eq!(
+            statuses,
+            [
+                (1203, "Present".to_string()),
+                (1204, "Late".to_string()),
+                (1205, "Excused".to_string()),
+                (1206, "Absent".to_string()),
+            ]
+        );
+        assert_eq!(
+            moodle
+                .get_present_status(&user, &sesskey(), fake_moodle::SESSION_ID)
+                .await
+                .unwrap(),
+            fake_moodle::PRESENT_STATUS_ID
+        );
+    }
+
+    #[tokio::test]
+    async fn gets_session_statuses_from_web_service() {
+        let fake = FakeMoodle::start().await;
+        let moodle = fake.client().await;
+
+        check_session_statuses(&moodle).await;
+        assert_eq!(
+            fake.ajax_calls(),
+            ["mod_attendance_get_session", "mod_attendance_get_session"]
+        );
+    }
+
+    #[tokio::test]
+    async fn scrapes_session_statuses_without_web_service() {
+        let fake = FakeMoodle::start().await;
+        let moodle = fake.client().await;
+        fake.disable_ajax();
+
+        check_session_statuses(&moodle).await;
+    }
+
+    #[tokio::test]
+    async fn web_service_errors_do_not_show_sesskey() {
+        #[derive(Serialize)]
+        struct Args {
+            sessionid: u32,
+        }
+
+        let fake = FakeMoodle::start().await;
+        let moodle = fake.client().await;
+        fake.break_ajax();
+
+        let error = moodle
+            .call_ajax::<_, AjaxSession>(
+                &user(fake_moodle::SESSION),
+                &sesskey(),
+                "mod_attendance_get_session",
+                Args {
+                    sessionid: fake_moodle::SESSION_ID,
+                },
+                Priority::Mark,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(error, MoodleError::Network(_)), "{:?}", error);
+
+        // the fallback warning and the mark outcomes show the error with its sources
+        let mut source: Option<&dyn std::error::Error> = Some(&error);
+        while let Some(e) = source {
+            assert!(!e.to_string().contains(fake_moodle::SESSKEY), "{}", e);
+            source = e.source();
+        }
+        assert!(!format!("{:?}", error).contains(fake_moodle::SESSKEY));
+
+        check_session_statuses(&moodle).await;
+    }
+
+    #[tokio::test]
+    async fn stale_sesskey_is_reported_as_expired_session() {
+        let fake = FakeMoodle::start().await;
+        let moodle = fake.client().await;
+
+        let result = moodle
+            .get_session_statuses(
+                &user(fake_moodle::SESSION),
+                &Secret::new("stale".to_string()),
+                fake_moodle::SESSION_ID,
+            )
+            .await;
+        assert!(
+            matches!(result, Err(MoodleError::SessionExpired)),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn marks_attendance() {
+        let fake = FakeMoodle::start().await;
+        let moodle = fake.client().await;
+
+        moodle
+            .mark_attendance_session(
+                &user(fake_moodle::SESSION),
+                &Secret::new(fake_moodle::SESSKEY.to_string()),
+                fake_moodle::SESSION_ID,
+                fake_moodle::PRESENT_STATUS_ID,
+                &Password::new(fake_moodle::PASSWORD.to_string()),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            fake.marks(),
+            [MarkForm {
+                sesskey: fake_moodle::SESSKEY.to_string(),
+                sessid: fake_moodle::SESSION_ID,
+                studentpassword: fake_moodle::PASSWORD.to_string(),
+                status: fake_moodle::PRESENT_STATUS_ID,
+            }]
+        );
+
+        // the report shows the mark now
+        let report = moodle
+            .get_attendance_report(fake_moodle::ACTIVITY_ID, &user(fake_moodle::SESSION))
+            .await
+            .unwrap();
+        let entry = report.iter().find(|e| e.date == date(14, 2)).unwrap();
+        assert!(entry.is_present());
+        assert_eq!(entry.session_id, None);
+        assert_eq!(entry.points.as_deref(), Some("2 / 2"));
+    }
+
+    #[tokio::test]
+    async fn reports_wrong_password() {
+        let fake = FakeMoodle::start().await;
+        let moodle = fake.client().await;
+
+        let result = moodle
+            .mark_attendance_session(
+                &user(fake_moodle::SESSION),
+                &Secret::new(fake_moodle::SESSKEY.to_string()),
+                fake_moodle::SESSION_ID,
+                fake_moodle::PRESENT_STATUS_ID,
+                &Password::new("wrong".to_string()),
+            )
+            .await;
+
+        assert!(
+            matches!(result, Err(MoodleError::WrongPassword)),
+            "{:?}",
+            result
+        );
+        assert!(fake.marks().is_empty());
+    }
+
+    #[tokio::test]
+    async fn fetches_present_status_once_per_session() {
+        let fake = FakeMoodle::start().await;
+        let moodle = fake.client().await;
+
+        let result = moodle
+            .mark_present(
+                &user(fake_moodle::SESSION),
+                &sesskey(),
+                fake_moodle::SESSION_ID,
+                &Password::new("wrong".to_string()),
+            )
+            .await;
+        assert!(
+            matches!(result, Err(MoodleError::WrongPassword)),
+            "{:?}",
+            result
+        );
+
+        moodle
+            .mark_present(
+                &user(fake_moodle::SESSION),
+                &sesskey(),
+                fake_moodle::SESSION_ID,
+                &Password::new(fake_moodle::PASSWORD.to_string()),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(fake.ajax_calls(), ["mod_attendance_get_session"]);
+        assert_eq!(
+            fake.marks(),
+            [MarkForm {
+                sesskey: fake_moodle::SESSKEY.to_string(),
+                sessid: fake_moodle::SESSION_ID,
+                studentpassword: fake_moodle::PASSWORD.to_string(),
+                status: fake_moodle::PRESENT_STATUS_ID,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_expired_session_when_marking() {
+        let fake = FakeMoodle::start().await;
+        let moodle = fake.client().await;
+
+        let result = moodle
+            .mark_attendance_session(
+                &user("expired"),
+                &Secret::new(fake_moodle::SESSKEY.to_string()),
+                fake_moodle::SESSION_ID,
+                fake_moodle::PRESENT_STATUS_ID,
+                &Password::new(fake_moodle::PASSWORD.to_string()),
+            )
+            .await;
+
+        assert!(
+            matches!(result, Err(MoodleError::SessionExpired)),
+            "{:?}",
+            result
+        );
+        assert!(fake.marks().is_empty());
     }
 }
@@ -0,0 +1,79 @@
+use crate::attendance::Attendance;
+use crate::attendance_backend::{AttendanceBackend, AttendanceSession, BackendUser, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+/// A value that was either fetched for the current user or taken from the cache
+#[derive(Debug)]
+pub struct Fetched<T> {
+    pub value: T,
+    /// Whether the value was fetched for some other user
+    pub from_cache: bool,
+}
+
+type Cell<T> = Arc<OnceCell<T>>;
+
+/// Caches the backend data that is (almost always) the same for all users during a single password event.
+///
+/// The session lists are cached by the activity id. Only the successful fetches are cached. The values were
+/// fetched for another user, so the callers should fall back to fetching them for the current user when they
+/// don't work.
+pub struct EventCache<'a> {
+    backend: &'a dyn AttendanceBackend,
+    sessions: Mutex<HashMap<u32, Cell<Vec<AttendanceSession>>>>,
+}
+
+impl<'a> EventCache<'a> {
+    pub fn new(backend: &'a dyn AttendanceBackend) -> Self {
+        Self {
+            backend,
+            sessions: Default::default(),
+        }
+    }
+
+    async fn get_or_fetch<T: Clone, F>(cell: Cell<T>, fetch: F) -> Result<Fetched<T>>
+    where
+        F: std::future::Future<Output = Result<T>>,
+    {
+        let mut fetched = false;
+        let value = cell
+            .get_or_try_init(|| {
+                fetched = true;
+                fetch
+            })
+            .await?;
+
+        Ok(Fetched {
+            value: value.clone(),
+            from_cache: !fetched,
+        })
+    }
+
+    /// The sessions open for marking on the date of the attendance, which is the same for the whole event
+    pub async fn attendance_sessions(
+        &self,
+        activity_id: u32,
+        user: &BackendUser,
+        attendance: &Attendance,
+    ) -> Result<Fetched<Vec<AttendanceSession>>> {
+        let cell = self
+            .sessions
+            .lock()
+            .unwrap()
+            .entry(activity_id)
+            .or_default()
+            .clone();
+
+        let fetch = async {
+            Ok(self
+                .backend
+                .list_sessions(activity_id, user, attendance)
+                .await?
+                .into_iter()
+                .filter_map(|entry| entry.session())
+                .collect())
+        };
+        Self::get_or_fetch(cell, fetch).await
+    }
+}
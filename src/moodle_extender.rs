@@ -1,5 +1,7 @@
 use crate::config;
+use crate::reqwest_retry::{Idempotent, RetryMiddleware};
 use crate::reqwest_span_backend::MoodleExtenderSpanBackend;
+use crate::secret::Secret;
 use anyhow::Result;
 use reqwest_tracing::TracingMiddleware;
 use serde::{Deserialize, Serialize};
@@ -7,8 +9,8 @@ use tracing::{instrument, trace};
 use url::Url;
 
 #[derive(Serialize)]
-struct ExtendRequest {
-    pub moodle_session: String,
+struct ExtendRequest<'a> {
+    pub moodle_session: &'a str,
 }
 
 #[derive(Deserialize)]
@@ -26,6 +28,7 @@ impl MoodleExtender {
     pub async fn new(config: &config::MoodleExtender) -> Result<Self> {
         Ok(MoodleExtender {
             reqwest: reqwest_middleware::ClientBuilder::new(reqwest::ClientBuilder::new().build()?)
+                .with(RetryMiddleware::new(&config.retry))
                 .with(TracingMiddleware::<MoodleExtenderSpanBackend>::new())
                 .build(),
             base_url: config.base_url.clone(),
@@ -33,17 +36,19 @@ impl MoodleExtender {
     }
 
     #[instrument(skip_all, err, ret)]
-    pub async fn extend_session(&self, session: &str) -> Result<Option<String>> {
-        trace!("Extending session {}...", session);
+    pub async fn extend_session(&self, session: &Secret<String>) -> Result<Option<String>> {
+        trace!("Extending session...");
 
         let rq = ExtendRequest {
-            moodle_session: session.to_string(),
+            moodle_session: session.expose(),
         };
 
         let res = self
             .reqwest
             .post(self.base_url.join("extend-session")?)
             .json(&rq)
+            // extending the session twice is harmless
+            .with_extension(Idempotent)
             .send()
             .await?;
 
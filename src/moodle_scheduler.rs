@@ -0,0 +1,237 @@
+use crate::metrics;
+use governor::clock::{Clock, DefaultClock};
+use governor::state::{InMemoryState, NotKeyed};
+use governor::{Quota, RateLimiter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Priority of a request to moodle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Requests submitting an attendance mark (and the ones it can't be done without)
+    Mark,
+    /// Everything else
+    Normal,
+}
+
+/// Distributes the moodle rate limit between the requests, letting the [`Priority::Mark`] ones go first.
+///
+/// A [`Priority::Normal`] request is only let through when no [`Priority::Mark`] requests are waiting,
+/// so that a mark submission for one user does not wait behind the profile checks of all the other users.
+/// It gives way to the marks for at most `max_normal_delay` though, after that it takes the next permit
+/// before them, so that a long password event does not block everything else.
+pub struct Scheduler {
+    rate_limiter: RateLimiter<NotKeyed, InMemoryState, DefaultClock>,
+    clock: DefaultClock,
+    max_normal_delay: Duration,
+    waiting_marks: Waiters,
+    overdue_normals: Waiters,
+}
+
+/// Counts the requests of some kind waiting for a permit
+struct Waiters {
+    count: AtomicUsize,
+    none_left: Notify,
+}
+
+/// Decrements the waiters counter even if the waiting future is dropped
+struct Waiting<'a>(&'a Waiters);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.none_left.notify_waiters();
+        }
+    }
+}
+
+impl Waiters {
+    fn new() -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            none_left: Notify::new(),
+        }
+    }
+
+    fn enter(&self) -> Waiting<'_> {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        Waiting(self)
+    }
+
+    /// Waits until there are no waiting requests of this kind
+    async fn until_none(&self) {
+        loop {
+            let none_left = self.none_left.notified();
+            tokio::pin!(none_left);
+            // register before checking the counter, so that we don't miss the notification
+            none_left.as_mut().enable();
+
+            if self.count.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            none_left.await;
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new(quota: Quota, max_normal_delay: Duration) -> Self {
+        Self {
+            rate_limiter: RateLimiter::direct(quota),
+            clock: DefaultClock::default(),
+            max_normal_delay,
+            waiting_marks: Waiters::new(),
+            overdue_normals: Waiters::new(),
+        }
+    }
+
+    /// Waits until a request with the given priority can be sent
+    pub async fn until_ready(&self, priority: Priority) {
+        let start = Instant::now();
+        self.wait(priority).await;
+
+        let label = match priority {
+            Priority::Mark => "mark",
+            Priority::Normal => "normal",
+        };
+        metrics::RATE_LIMIT_WAIT
+            .with_label_values(&[label])
+            .observe(start.elapsed().as_secs_f64());
+    }
+
+    async fn wait(&self, priority: Priority) {
+        match priority {
+            Priority::Mark => {
+                let _waiting = self.waiting_marks.enter();
+
+                loop {
+                    self.overdue_normals.until_none().await;
+                    if self.try_acquire().await {
+                        return;
+                    }
+                }
+            }
+            Priority::Normal => {
+                let deadline = tokio::time::Instant::now() + self.max_normal_delay;
+                loop {
+                    let marks_done =
+                        tokio::time::timeout_at(deadline, self.waiting_marks.until_none()).await;
+                    if marks_done.is_err() {
+                        // gave way to the marks for long enough, now they give way to us
+                        let _overdue = self.overdue_normals.enter();
+                        while !self.try_acquire().await {}
+                        return;
+                    }
+                    if self.try_acquire().await {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes a permit from the rate limiter if one is available, otherwise waits until it should be
+    async fn try_acquire(&self) -> bool {
+        match self.rate_limiter.check() {
+            Ok(()) => true,
+            Err(not_until) => {
+                tokio::time::sleep(not_until.wait_time_from(self.clock.now())).await;
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU32;
+    use std::sync::{Arc, Mutex};
+
+    /// A permit each `period`, with no burst
+    fn scheduler(period: Duration, max_normal_delay: Duration) -> Arc<Scheduler> {
+        let quota = Quota::with_period(period)
+            .unwrap()
+            .allow_burst(NonZeroU32::new(1).unwrap());
+        Arc::new(Scheduler::new(quota, max_normal_delay))
+    }
+
+    /// Spawns a request waiting for the scheduler, which records its name when it is let through
+    fn spawn_request(
+        scheduler: &Arc<Scheduler>,
+        order: &Arc<Mutex<Vec<String>>>,
+        priority: Priority,
+        name: String,
+    ) -> tokio::task::JoinHandle<()> {
+        let scheduler = scheduler.clone();
+        let order = order.clone();
+        tokio::spawn(async move {
+            scheduler.until_ready(priority).await;
+            order.lock().unwrap().push(name);
+        })
+    }
+
+    #[tokio::test]
+    async fn lets_marks_go_first() {
+        let scheduler = scheduler(Duration::from_millis(50), Duration::from_secs(60));
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        // use up the permit, so that everything below has to wait
+        scheduler.until_ready(Priority::Normal).await;
+
+        let normal = spawn_request(&scheduler, &order, Priority::Normal, "normal".into());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let marks = (0..3)
+            .map(|i| spawn_request(&scheduler, &order, Priority::Mark, format!("mark {}", i)))
+            .collect::<Vec<_>>();
+
+        for mark in marks {
+            mark.await.unwrap();
+        }
+        normal.await.unwrap();
+
+        let order = order.lock().unwrap();
+        assert_eq!(order.last().unwrap(), "normal", "{:?}", order);
+    }
+
+    #[tokio::test]
+    async fn lets_normal_requests_through_when_there_are_no_marks() {
+        let scheduler = scheduler(Duration::from_millis(50), Duration::from_secs(60));
+
+        let start = Instant::now();
+        scheduler.until_ready(Priority::Normal).await;
+        scheduler.until_ready(Priority::Normal).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn does_not_starve_normal_requests() {
+        let scheduler = scheduler(Duration::from_millis(20), Duration::from_millis(100));
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        scheduler.until_ready(Priority::Mark).await;
+
+        // a second worth of marks
+        let marks = (0..50)
+            .map(|i| spawn_request(&scheduler, &order, Priority::Mark, format!("mark {}", i)))
+            .collect::<Vec<_>>();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let normal = spawn_request(&scheduler, &order, Priority::Normal, "normal".into());
+
+        normal.await.unwrap();
+        for mark in marks {
+            mark.await.unwrap();
+        }
+
+        // the normal request waits for ~5 marks and then competes with the rest of them
+        let order = order.lock().unwrap();
+        let position = order.iter().position(|name| name == "normal").unwrap();
+        assert!(
+            position < 25,
+            "normal went through at {}: {:?}",
+            position,
+            order
+        );
+    }
+}
@@ -0,0 +1,383 @@
+//! Extracts the data from the moodle pages.
+//!
+//! These are pure functions over the page HTML, so that they can be tested against the saved pages in
+//! `fixtures/moodle` without talking to moodle.
+
+use crate::moodle::{AttendanceReportEntry, MoodleError, Result};
+use crate::secret::Secret;
+use chrono::NaiveDate;
+use email_address::EmailAddress;
+use once_cell::sync::{Lazy, OnceCell};
+use regex::Regex;
+use scraper::{ElementRef, Html, Selector};
+use tracing::trace;
+use url::Url;
+
+static EMAIL_EXTRACT_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"<dt>(?:Email address|Адрес электронной почты)</dt><dd><a href="([^"]+)">"#)
+        .unwrap()
+});
+static SESSION_EXTRACT_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#""sesskey":"([^"]+)""#).unwrap());
+/// The own profile page links to the profile editing page, which has the user id
+static USER_ID_EXTRACT_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"/user/edit\.php\?id=(\d+)"#).unwrap());
+
+/// A lazily parsed CSS selector that remembers its source to report it when the layout changes
+struct LazySelector {
+    css: &'static str,
+    selector: OnceCell<Selector>,
+}
+
+impl LazySelector {
+    const fn new(css: &'static str) -> Self {
+        Self {
+            css,
+            selector: OnceCell::new(),
+        }
+    }
+
+    fn get(&self) -> &Selector {
+        self.selector
+            .get_or_init(|| Selector::parse(self.css).expect("hard-coded selector should be valid"))
+    }
+
+    fn first<'a>(&self, element: ElementRef<'a>) -> Result<ElementRef<'a>> {
+        element
+            .select(self.get())
+            .next()
+            .ok_or(MoodleError::LayoutChanged { selector: self.css })
+    }
+}
+
+/// Extracts the user's email from the `/user/profile.php` page
+pub fn parse_email(body: &str) -> Result<String> {
+    let encoded_email = EMAIL_EXTRACT_REGEX
+        .captures(body)
+        .ok_or(MoodleError::LayoutChanged {
+            selector: EMAIL_EXTRACT_REGEX.as_str(),
+        })?
+        .get(1)
+        .unwrap()
+        .as_str();
+
+    let email = urlencoding::decode(encoded_email).map_err(|_| {
+        MoodleError::InvalidResponse(format!("could not decode email {:?}", encoded_email))
+    })?;
+    let email = html_escape::decode_html_entities(&email);
+    let email = email.strip_prefix("mailto:").ok_or_else(|| {
+        MoodleError::InvalidResponse(format!("email link {:?} is not mailto", email))
+    })?;
+
+    if !EmailAddress::is_valid(email) {
+        return Err(MoodleError::InvalidResponse(format!(
+            "extracted email address {}, but it seems to be invalid",
+            email
+        )));
+    }
+
+    Ok(email.to_string())
+}
+
+/// Extracts the CSRF token of the session from any moodle page
+pub fn parse_sesskey(body: &str) -> Result<Secret<String>> {
+    let sesskey = SESSION_EXTRACT_REGEX
+        .captures(body)
+        .ok_or(MoodleError::LayoutChanged {
+            selector: SESSION_EXTRACT_REGEX.as_str(),
+        })?
+        .get(1)
+        .unwrap()
+        .as_str();
+
+    Ok(sesskey.to_string().into())
+}
+
+/// Extracts the id of the user from the `/user/profile.php` page, if the page links to it
+pub fn parse_user_id(body: &str) -> Option<u32> {
+    USER_ID_EXTRACT_REGEX
+        .captures(body)
+        .and_then(|c| c.get(1).unwrap().as_str().parse().ok())
+}
+
+/// Parses the date of a session as shown in the attendance report
+fn parse_session_date(date: &str) -> Result<NaiveDate> {
+    static DATE_FORMATS: [&str; 2] = [
+        // 23.01.23 (Mon), the weekday is localized and chrono only knows the english names, so it is cut off
+        "%d.%m.%y",
+        // Mon 23 Jan 2023
+        "%a %d %b %Y",
+    ];
+
+    let without_weekday = date.split_once(" (").map_or(date, |(date, _)| date);
+    DATE_FORMATS
+        .into_iter()
+        .find_map(|fmt| {
+            NaiveDate::parse_from_str(date, fmt)
+                .or_else(|_| NaiveDate::parse_from_str(without_weekday, fmt))
+                .ok()
+        })
+        .ok_or_else(|| MoodleError::InvalidResponse(format!("could not parse date {:?}", date)))
+}
+
+/// Parses the user's attendance report (`/mod/attendance/view.php?view=5`)
+pub fn parse_attendance_report(body: &str) -> Result<Vec<AttendanceReportEntry>> {
+    static TABLE_SELECTOR: LazySelector =
+        LazySelector::new("table.generaltable.attwidth.boxaligncenter > tbody");
+    static DATE_SELECTOR: LazySelector = LazySelector::new("td:nth-of-type(1)");
+    static STATUS_SELECTOR: LazySelector = LazySelector::new("td:nth-of-type(3)");
+    static LINK_SELECTOR: LazySelector = LazySelector::new("td:nth-of-type(3) > a");
+    static POINTS_SELECTOR: LazySelector = LazySelector::new("td:nth-of-type(4)");
+
+    let page = Html::parse_document(body);
+    let table = TABLE_SELECTOR.first(page.root_element())?;
+
+    let mut result = Vec::new();
+    for session in table.children() {
+        // skip non-element nodes
+        let Some(session) = ElementRef::wrap(session) else {
+            continue;
+        };
+
+        trace!("Session element: {:?}", session.value());
+
+        let date = DATE_SELECTOR
+            .first(session)?
+            .text()
+            .next()
+            .ok_or(MoodleError::LayoutChanged {
+                selector: DATE_SELECTOR.css,
+            })?
+            .trim();
+        let date = parse_session_date(date)?;
+
+        let points = session
+            .select(POINTS_SELECTOR.get())
+            .next()
+            .map(|v| v.text().collect::<String>().trim().to_string())
+            .filter(|v| !v.is_empty());
+
+        let Some(link) = session.select(LINK_SELECTOR.get()).next() else {
+            // no link means that the attendance was either taken or the session is closed
+            // moodle shows "?" for the sessions that were not taken yet
+            let status = session
+                .select(STATUS_SELECTOR.get())
+                .next()
+                .map(|v| v.text().collect::<String>().trim().to_string())
+                .filter(|v| !v.is_empty() && v != "?");
+
+            result.push(AttendanceReportEntry {
+                date,
+                session_id: None,
+                status,
+                points,
+            });
+            continue;
+        };
+
+        let link = link
+            .value()
+            .attr("href")
+            .ok_or(MoodleError::LayoutChanged {
+                selector: LINK_SELECTOR.css,
+            })?;
+        let link = Url::parse(link)?;
+        let id = link
+            .query_pairs()
+            .find(|(k, _)| k == "sessid")
+            .map(|(_, v)| v)
+            .ok_or_else(|| {
+                MoodleError::InvalidResponse(format!("could not find sessid in {}", link))
+            })?
+            .parse::<u32>()
+            .map_err(|_| {
+                MoodleError::InvalidResponse(format!("could not parse sessid in {}", link))
+            })?;
+
+        result.push(AttendanceReportEntry {
+            date,
+            session_id: Some(id),
+            status: None,
+            points,
+        });
+    }
+
+    Ok(result)
+}
+
+/// Parses the statuses offered in the attendance submission form (`/mod/attendance/attendance.php`)
+pub fn parse_session_statuses(body: &str) -> Result<Vec<(u32, String)>> {
+    static LABELS_SELECTOR: LazySelector = LazySelector::new("#fgroup_id_statusarray label");
+    static INPUT_SELECTOR: LazySelector = LazySelector::new("input");
+
+    let page = Html::parse_document(body);
+
+    let mut result = Vec::new();
+    for label in page.select(LABELS_SELECTOR.get()) {
+        let name = label
+            .text()
+            .find(|v| !v.trim().is_empty())
+            .ok_or(MoodleError::LayoutChanged {
+                selector: LABELS_SELECTOR.css,
+            })?
+            .trim();
+
+        let value = INPUT_SELECTOR.first(label)?.value().attr("value").ok_or(
+            MoodleError::LayoutChanged {
+                selector: INPUT_SELECTOR.css,
+            },
+        )?;
+        let id = value.parse::<u32>().map_err(|_| {
+            MoodleError::InvalidResponse(format!("could not parse status id {:?}", value))
+        })?;
+
+        result.push((id, name.to_string()));
+    }
+
+    Ok(result)
+}
+
+/// Extracts the error moodle shows either as a notification or as a form validation error
+pub fn extract_page_error(body: &str) -> Option<String> {
+    static ERROR_SELECTOR: LazySelector =
+        LazySelector::new(".alert-danger, .alert-error, .invalid-feedback, .error");
+
+    // the notifications have a close button with "×" and "Dismiss this notification" in them, skip it
+    fn collect_text(element: ElementRef, result: &mut String) {
+        for child in element.children() {
+            if let Some(text) = child.value().as_text() {
+                result.push_str(text);
+            } else if let Some(child) = ElementRef::wrap(child) {
+                if child.value().name() != "button" {
+                    collect_text(child, result);
+                }
+            }
+        }
+    }
+
+    let page = Html::parse_document(body);
+    page.select(ERROR_SELECTOR.get())
+        .map(|e| {
+            let mut text = String::new();
+            collect_text(e, &mut text);
+            text.trim().to_string()
+        })
+        .find(|e| !e.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::path::Path;
+
+    /// Runs the parser over all the pages in `fixtures/moodle/<kind>` and compares the results
+    /// with the `.json` files next to them.
+    ///
+    /// Run with `UPDATE_GOLDEN=1` to write the current results instead, and review the diff.
+    fn check_golden(kind: &str, parse: impl Fn(&str) -> Value) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/moodle")
+            .join(kind);
+        let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+
+        let mut pages = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("html".as_ref()))
+            .collect::<Vec<_>>();
+        pages.sort();
+        assert!(!pages.is_empty(), "no pages in {}", dir.display());
+
+        let mut mismatches = Vec::new();
+        for page in pages {
+            let actual = parse(&std::fs::read_to_string(&page).unwrap());
+            let golden = page.with_extension("json");
+
+            if update {
+                std::fs::write(
+                    &golden,
+                    serde_json::to_string_pretty(&actual).unwrap() + "\n",
+                )
+                .unwrap();
+                continue;
+            }
+
+            let expected = std::fs::read_to_string(&golden).unwrap_or_else(|e| {
+                panic!(
+                    "could not read {} ({}), run with UPDATE_GOLDEN=1 to create it",
+                    golden.display(),
+                    e
+                )
+            });
+            let expected: Value = serde_json::from_str(&expected).unwrap();
+            if actual != expected {
+                mismatches.push(format!(
+                    "{}:\nexpected: {}\n  actual: {}",
+                    page.display(),
+                    expected,
+                    actual
+                ));
+            }
+        }
+
+        assert!(mismatches.is_empty(), "{}", mismatches.join("\n\n"));
+    }
+
+    fn to_json<T>(result: Result<T>, ok: impl FnOnce(T) -> Value) -> Value {
+        match result {
+            Ok(value) => ok(value),
+            Err(e) => json!({ "error": e.to_string() }),
+        }
+    }
+
+    #[test]
+    fn profile_pages() {
+        check_golden("profile", |body| {
+            let profile = parse_email(body).and_then(|email| Ok((email, parse_sesskey(body)?)));
+            let mut result = to_json(
+                profile,
+                |(email, sesskey)| json!({ "email": email, "sesskey": sesskey.expose() }),
+            );
+            // the user id is looked up even when the email can't be scraped
+            result["user_id"] = json!(parse_user_id(body));
+            result
+        });
+    }
+
+    #[test]
+    fn report_pages() {
+        check_golden("report", |body| {
+            to_json(parse_attendance_report(body), |entries| {
+                entries
+                    .into_iter()
+                    .map(|entry| {
+                        json!({
+                            "date": entry.date.to_string(),
+                            "session_id": entry.session_id,
+                            "status": entry.status,
+                            "points": entry.points,
+                        })
+                    })
+                    .collect()
+            })
+        });
+    }
+
+    #[test]
+    fn session_pages() {
+        check_golden("session", |body| {
+            to_json(parse_session_statuses(body), |statuses| {
+                statuses
+                    .into_iter()
+                    .map(|(id, name)| json!({ "id": id, "name": name }))
+                    .collect()
+            })
+        });
+    }
+
+    #[test]
+    fn error_pages() {
+        check_golden("errors", |body| json!(extract_page_error(body)));
+    }
+}
@@ -0,0 +1,367 @@
+use crate::config;
+use crate::moodle_scheduler::{Priority, Scheduler};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Method, Request, Response, StatusCode};
+use reqwest_middleware::{Middleware, Next, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use task_local_extensions::Extensions;
+use tracing::warn;
+
+/// Marks a non-GET request as safe to retry.
+///
+/// Add it with [`reqwest_middleware::RequestBuilder::with_extension`].
+#[derive(Clone, Copy)]
+pub struct Idempotent;
+
+/// Number of the current attempt (0 for the first one), stored in the request extensions.
+#[derive(Clone, Copy)]
+struct RetryAttempt(u32);
+
+/// Returns the number of the current attempt, so that it can be recorded by the span backends.
+pub fn retry_attempt(extensions: &Extensions) -> u32 {
+    extensions.get::<RetryAttempt>().map_or(0, |a| a.0)
+}
+
+/// The longest `Retry-After` we are willing to wait for, the request fails instead of waiting longer
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// Retries idempotent requests failing with transient errors, with exponential backoff.
+///
+/// Should be added before the [`reqwest_tracing::TracingMiddleware`], so that each attempt gets its own span.
+pub struct RetryMiddleware {
+    attempts: u32,
+    backoff: Duration,
+    /// The retries take a permit from the same rate limiter as the first attempts,
+    /// with the [`Priority`] from the request extensions
+    scheduler: Option<Arc<Scheduler>>,
+}
+
+impl RetryMiddleware {
+    pub fn new(config: &config::Retry) -> Self {
+        Self {
+            attempts: config.attempts.max(1),
+            backoff: config.backoff,
+            scheduler: None,
+        }
+    }
+
+    pub fn with_scheduler(self, scheduler: Arc<Scheduler>) -> Self {
+        Self {
+            scheduler: Some(scheduler),
+            ..self
+        }
+    }
+
+    /// How long to wait before the next attempt (`attempt` is the number of the attempts made),
+    /// `None` if the request should not be retried
+    fn retry_delay(&self, attempt: u32, result: &Result<Response>) -> Option<Duration> {
+        if let Ok(resp) = result {
+            if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+                // retrying right away would only make it worse, wait as long as we are told to
+                return retry_after(resp).filter(|delay| *delay <= MAX_RETRY_AFTER);
+            }
+        }
+
+        is_transient(result).then(|| self.backoff * 2u32.saturating_pow(attempt - 1))
+    }
+}
+
+/// Parses the `Retry-After` header in the delay-seconds form
+fn retry_after(resp: &Response) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+fn is_idempotent(req: &Request, extensions: &Extensions) -> bool {
+    matches!(*req.method(), Method::GET | Method::HEAD) || extensions.get::<Idempotent>().is_some()
+}
+
+fn is_transient(result: &Result<Response>) -> bool {
+    match result {
+        Ok(resp) => matches!(
+            resp.status(),
+            StatusCode::REQUEST_TIMEOUT
+                | StatusCode::INTERNAL_SERVER_ERROR
+                | StatusCode::BAD_GATEWAY
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT
+        ),
+        Err(reqwest_middleware::Error::Reqwest(e)) => {
+            e.is_timeout() || e.is_connect() || e.is_request()
+        }
+        Err(reqwest_middleware::Error::Middleware(_)) => false,
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for RetryMiddleware {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> Result<Response> {
+        if !is_idempotent(&req, extensions) {
+            return next.run(req, extensions).await;
+        }
+
+        let mut attempt = 0;
+        loop {
+            // streaming bodies can't be cloned, so we can't retry them
+            let Some(attempt_req) = req.try_clone() else {
+                return next.run(req, extensions).await;
+            };
+
+            extensions.insert(RetryAttempt(attempt));
+            let result = next.clone().run(attempt_req, extensions).await;
+
+            attempt += 1;
+            if attempt >= self.attempts {
+                return result;
+            }
+            let Some(delay) = self.retry_delay(attempt, &result) else {
+                return result;
+            };
+
+            match &result {
+                Ok(resp) => warn!(
+                    "{} {} responded with {}, retrying in {:?}",
+                    req.method(),
+                    req.url().path(),
+                    resp.status(),
+                    delay
+                ),
+                Err(e) => warn!(
+                    "{} {} failed with {}, retrying in {:?}",
+                    req.method(),
+                    req.url().path(),
+                    e,
+                    delay
+                ),
+            }
+            tokio::time::sleep(delay).await;
+
+            if let Some(scheduler) = &self.scheduler {
+                let priority = extensions
+                    .get::<Priority>()
+                    .copied()
+                    .unwrap_or(Priority::Normal);
+                scheduler.until_ready(priority).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::http::HeaderMap;
+    use axum::routing::any;
+    use axum::Router;
+    use governor::Quota;
+    use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
+    use std::net::{SocketAddr, TcpListener};
+    use std::num::NonZeroU32;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use url::Url;
+
+    /// A server responding with the status and `Retry-After` from the query to every request, counting them
+    struct FlakyServer {
+        url: Url,
+        requests: Arc<AtomicU32>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Query {
+        status: u16,
+        retry_after: Option<String>,
+    }
+
+    impl FlakyServer {
+        async fn start() -> Self {
+            async fn respond(
+                State(requests): State<Arc<AtomicU32>>,
+                axum::extract::Query(query): axum::extract::Query<Query>,
+            ) -> (axum::http::StatusCode, HeaderMap) {
+                requests.fetch_add(1, Ordering::SeqCst);
+
+                let mut headers = HeaderMap::new();
+                if let Some(retry_after) = query.retry_after {
+                    headers.insert("retry-after", retry_after.parse().unwrap());
+                }
+                (
+                    axum::http::StatusCode::from_u16(query.status).unwrap(),
+                    headers,
+                )
+            }
+
+            let requests = Arc::new(AtomicU32::new(0));
+            let app = Router::new()
+                .route("/", any(respond))
+                .with_state(requests.clone());
+
+            let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+            let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+            tokio::spawn(
+                axum::Server::from_tcp(listener)
+                    .unwrap()
+                    .serve(app.into_make_service()),
+            );
+
+            Self { url, requests }
+        }
+
+        fn url(&self, status: u16, retry_after: Option<&str>) -> Url {
+            let mut url = self.url.clone();
+            url.query_pairs_mut()
+                .append_pair("status", &status.to_string());
+            if let Some(retry_after) = retry_after {
+                url.query_pairs_mut()
+                    .append_pair("retry_after", retry_after);
+            }
+            url
+        }
+
+        fn requests(&self) -> u32 {
+            self.requests.load(Ordering::SeqCst)
+        }
+    }
+
+    fn retry(attempts: u32) -> RetryMiddleware {
+        RetryMiddleware::new(&config::Retry {
+            attempts,
+            backoff: Duration::ZERO,
+        })
+    }
+
+    fn client(middleware: RetryMiddleware) -> ClientWithMiddleware {
+        ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn retries_transient_statuses_of_idempotent_requests() {
+        let client = client(retry(3));
+
+        for status in [408, 500, 502, 503, 504] {
+            let server = FlakyServer::start().await;
+            let resp = client.get(server.url(status, None)).send().await.unwrap();
+            assert_eq!(resp.status().as_u16(), status);
+            assert_eq!(server.requests(), 3, "status {}", status);
+        }
+
+        for status in [200, 400, 403, 404] {
+            let server = FlakyServer::start().await;
+            client.get(server.url(status, None)).send().await.unwrap();
+            assert_eq!(server.requests(), 1, "status {}", status);
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_only_idempotent_methods() {
+        let client = client(retry(3));
+
+        let server = FlakyServer::start().await;
+        client.post(server.url(503, None)).send().await.unwrap();
+        assert_eq!(server.requests(), 1);
+
+        let server = FlakyServer::start().await;
+        client
+            .post(server.url(503, None))
+            .with_extension(Idempotent)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(server.requests(), 3);
+
+        let server = FlakyServer::start().await;
+        client.head(server.url(503, None)).send().await.unwrap();
+        assert_eq!(server.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn retries_rate_limited_requests_only_when_told_when() {
+        let client = client(retry(3));
+
+        let server = FlakyServer::start().await;
+        client.get(server.url(429, None)).send().await.unwrap();
+        assert_eq!(server.requests(), 1);
+
+        // too far in the future
+        let server = FlakyServer::start().await;
+        client
+            .get(server.url(429, Some("3600")))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(server.requests(), 1);
+
+        let server = FlakyServer::start().await;
+        client.get(server.url(429, Some("0"))).send().await.unwrap();
+        assert_eq!(server.requests(), 3);
+    }
+
+    #[test]
+    fn backoff_doubles_with_each_attempt() {
+        let middleware = RetryMiddleware::new(&config::Retry {
+            attempts: 5,
+            backoff: Duration::from_millis(100),
+        });
+        let unavailable: Result<Response> = Ok(axum::http::Response::builder()
+            .status(503)
+            .body("")
+            .unwrap()
+            .into());
+
+        let delays = (1..=4)
+            .map(|attempt| middleware.retry_delay(attempt, &unavailable))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800].map(|ms| Some(Duration::from_millis(ms)))
+        );
+    }
+
+    #[test]
+    fn waits_as_long_as_the_server_asks() {
+        let middleware = RetryMiddleware::new(&config::Retry {
+            attempts: 5,
+            backoff: Duration::from_millis(100),
+        });
+        let rate_limited: Result<Response> = Ok(axum::http::Response::builder()
+            .status(429)
+            .header("retry-after", "7")
+            .body("")
+            .unwrap()
+            .into());
+
+        assert_eq!(
+            middleware.retry_delay(1, &rate_limited),
+            Some(Duration::from_secs(7))
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_take_rate_limiter_permits() {
+        // a single permit, the next one comes only in an hour
+        let scheduler = Arc::new(Scheduler::new(
+            Quota::per_hour(NonZeroU32::new(1).unwrap()),
+            Duration::ZERO,
+        ));
+        let client = client(retry(3).with_scheduler(scheduler));
+        let server = FlakyServer::start().await;
+
+        // the first attempt is made by the caller after taking its own permit, the first retry takes the only
+        // one in the limiter and the second one has to wait
+        let result = tokio::time::timeout(
+            Duration::from_millis(500),
+            client.get(server.url(503, None)).send(),
+        )
+        .await;
+        assert!(result.is_err(), "the second retry should wait for a permit");
+        assert_eq!(server.requests(), 2);
+    }
+}
@@ -1,12 +1,19 @@
-use reqwest::{Request, Response};
+use crate::metrics;
+use crate::reqwest_retry::retry_attempt;
+use reqwest::{Method, Request, Response};
 use reqwest_tracing::{default_on_request_end, reqwest_otel_span, ReqwestOtelSpanBackend};
+use std::time::Instant;
 use task_local_extensions::Extensions;
 
 pub struct MoodleExtenderSpanBackend;
 
 impl ReqwestOtelSpanBackend for MoodleExtenderSpanBackend {
-    fn on_request_start(req: &Request, _: &mut Extensions) -> tracing::Span {
-        reqwest_otel_span!(name = "moodle_extender/extend", req,)
+    fn on_request_start(req: &Request, extensions: &mut Extensions) -> tracing::Span {
+        reqwest_otel_span!(
+            name = "moodle_extender/extend",
+            req,
+            http.retry_count = retry_attempt(extensions)
+        )
     }
 
     fn on_request_end(
@@ -18,22 +25,47 @@ impl ReqwestOtelSpanBackend for MoodleExtenderSpanBackend {
     }
 }
 
+/// Remembered when the request is started, to measure its latency
+#[derive(Clone)]
+struct RequestStart {
+    at: Instant,
+    method: Method,
+    path: String,
+}
+
 pub struct MoodleSpanBackend;
 
 impl ReqwestOtelSpanBackend for MoodleSpanBackend {
-    fn on_request_start(req: &Request, _: &mut Extensions) -> tracing::Span {
+    fn on_request_start(req: &Request, extensions: &mut Extensions) -> tracing::Span {
+        extensions.insert(RequestStart {
+            at: Instant::now(),
+            method: req.method().clone(),
+            path: req.url().path().to_string(),
+        });
+
         reqwest_otel_span!(
             name = format!("moodle {} {}", req.method(), req.url().path()),
             req,
-            time_elapsed_ms = tracing::field::Empty
+            time_elapsed_ms = tracing::field::Empty,
+            http.retry_count = retry_attempt(extensions)
         )
     }
 
     fn on_request_end(
         span: &tracing::Span,
         outcome: &reqwest_middleware::Result<Response>,
-        _: &mut Extensions,
+        extensions: &mut Extensions,
     ) {
         default_on_request_end(span, outcome);
+
+        if let Some(start) = extensions.get::<RequestStart>() {
+            let status = match outcome {
+                Ok(resp) => resp.status().as_str().to_string(),
+                Err(_) => "error".to_string(),
+            };
+            metrics::MOODLE_REQUEST_DURATION
+                .with_label_values(&[start.method.as_str(), &start.path, &status])
+                .observe(start.at.elapsed().as_secs_f64());
+        }
     }
 }
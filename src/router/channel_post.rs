@@ -1,15 +1,23 @@
-use crate::attendance::Attendance;
+use crate::attendance::{Attendance, MarkOutcome};
+use crate::attendance_backend::{
+    self, AttendanceBackend, AttendanceSession, BackendError, BackendUser, SessionEntry, Validation,
+};
 use crate::config::BotChannel;
-use crate::moodle::{Moodle, SessionProbeResult};
+use crate::moodle_cache::EventCache;
 use crate::router::{MyStorage, State};
-use crate::{config, MyBot};
+use crate::secret::Secret;
+use crate::storage::{RegisteredUser, ValidationResult};
+use crate::{config, metrics, MyBot};
 use anyhow::Result;
+use futures::{stream, StreamExt};
 use once_cell::sync::Lazy;
 use regex::Regex;
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use teloxide::prelude::*;
-use teloxide::utils::html::{bold, code_inline, link};
-use tracing::{debug, error, info, instrument};
+use teloxide::utils::html::{bold, code_inline, escape, link};
+use tracing::{debug, error, info, instrument, warn};
 use url::Url;
 
 static PASSWORD_REGEX: Lazy<Regex> = Lazy::new(|| {
@@ -20,7 +28,7 @@ fn parse_attendance(text: &str) -> Option<Attendance> {
     PASSWORD_REGEX.captures(text).and_then(|cap| {
         let day = cap.name("day").unwrap().as_str().parse::<u8>().ok()?;
         let month = cap.name("month").unwrap().as_str().parse::<u8>().ok()?;
-        let password = cap.name("password").unwrap().as_str().to_string();
+        let password = cap.name("password").unwrap().as_str().to_string().into();
 
         Some(Attendance {
             day,
@@ -49,31 +57,220 @@ fn format_failure_message(
             Solutions::ManuallyMarkAt => {
                 format!(
                     "Please do it manually, the password is {}\n\n{}",
-                    code_inline(&attendance.password),
+                    code_inline(attendance.password.expose()),
                     link(manual_url.as_str(), manual_url.as_str()),
                 )
             }
             Solutions::ReRegister => {
-                format!("You should re-register with /start command to not miss further attendance marks.\n\nFor now you can do it manually, the password is {}\n\n{}", code_inline(&attendance.password), link(manual_url.as_str(), manual_url.as_str()))
+                format!("You should re-register with /start command to not miss further attendance marks.\n\nFor now you can do it manually, the password is {}\n\n{}", code_inline(attendance.password.expose()), link(manual_url.as_str(), manual_url.as_str()))
             }
             Solutions::Register => {
-                format!("You should register with /start command to not miss further attendance marks.\n\nFor now you can do it manually, the password is {}\n\n{}", code_inline(&attendance.password), link(manual_url.as_str(), manual_url.as_str()))
+                format!("You should register with /start command to not miss further attendance marks.\n\nFor now you can do it manually, the password is {}\n\n{}", code_inline(attendance.password.expose()), link(manual_url.as_str(), manual_url.as_str()))
             }
         }
     )
 }
 
+/// Records mark outcomes of a single attendance event into the history.
+struct History<'a> {
+    storage: &'a MyStorage,
+    /// `None` if the event could not be stored, the outcomes are then only counted in the metrics
+    event_id: Option<i64>,
+    /// The chats an outcome was recorded for
+    recorded: Mutex<HashSet<ChatId>>,
+}
+
+impl History<'_> {
+    async fn record(&self, chat_id: ChatId, session_id: Option<u32>, outcome: MarkOutcome) {
+        metrics::MARK_OUTCOMES
+            .with_label_values(&[outcome.kind()])
+            .inc();
+        self.recorded.lock().unwrap().insert(chat_id);
+
+        let Some(event_id) = self.event_id else {
+            return;
+        };
+        // failing to write the history should not prevent us from marking the attendance
+        if let Err(e) = self
+            .storage
+            .record_outcome(event_id, chat_id, session_id, &outcome)
+            .await
+        {
+            error!("Failed to record outcome {:?}: {:?}", outcome, e);
+        }
+    }
+
+    /// Records a failure to mark caused by a backend error
+    async fn record_error(&self, chat_id: ChatId, session_id: Option<u32>, e: &BackendError) {
+        metrics::MARK_ERRORS.with_label_values(&[e.kind()]).inc();
+        self.record(chat_id, session_id, failure_outcome(e)).await;
+    }
+
+    fn has_recorded(&self, chat_id: ChatId) -> bool {
+        self.recorded.lock().unwrap().contains(&chat_id)
+    }
+
+    /// Records the result of the session check done before marking
+    async fn record_validation(&self, chat_id: ChatId, result: ValidationResult) {
+        if let Err(e) = self.storage.record_validation(chat_id, result).await {
+            error!("Failed to record validation result {:?}: {:?}", result, e);
+        }
+    }
+}
+
+/// Reads back the attendance report to make sure moodle actually recorded the user as present.
+///
+/// Returns the report entry for the marked session if it was.
+async fn confirm_marked(
+    backend: &dyn AttendanceBackend,
+    activity_id: u32,
+    user: &BackendUser,
+    attendance: &Attendance,
+    session: &AttendanceSession,
+) -> attendance_backend::Result<Option<SessionEntry>> {
+    let report = backend.list_sessions(activity_id, user, attendance).await?;
+
+    // if the session is still open for submission, the mark did not go through
+    if report.iter().any(|e| e.session_id == Some(session.id)) {
+        return Ok(None);
+    }
+
+    Ok(report
+        .into_iter()
+        .find(|e| e.date == session.date && e.present))
+}
+
+/// Explains to the user why we could not mark the attendance
+fn failure_reason(e: &BackendError) -> Cow<'static, str> {
+    match e {
+        BackendError::WrongPassword => "moodle says the password is incorrect".into(),
+        BackendError::SessionClosed => "the session is already closed".into(),
+        BackendError::NoPresentStatus => {
+            "moodle does not offer the \"Present\" status for this session".into()
+        }
+        BackendError::Rejected(message) => format!("moodle said: {}", escape(message)).into(),
+        BackendError::SessionExpired => "your session has become invalid".into(),
+        BackendError::RateLimited => "moodle is overloaded right now".into(),
+        BackendError::Unreachable(_) => "I could not reach moodle".into(),
+        BackendError::Unexpected(_) => {
+            "I could not understand the moodle page (contact the developer pls)".into()
+        }
+    }
+}
+
+fn failure_outcome(e: &BackendError) -> MarkOutcome {
+    match e {
+        BackendError::SessionExpired => MarkOutcome::SessionInvalid,
+        e => MarkOutcome::Error(e.to_string()),
+    }
+}
+
+fn failure_solution(e: &BackendError) -> Solutions {
+    match e {
+        BackendError::SessionExpired => Solutions::ReRegister,
+        _ => Solutions::ManuallyMarkAt,
+    }
+}
+
+/// Marks the user in all sessions matching the attendance, returning the result for each session.
+///
+/// The session ids are taken from the event cache first, as they are the same for most users.
+/// If marking with them fails, they are fetched again for this user.
+async fn mark_sessions(
+    backend: &dyn AttendanceBackend,
+    cache: &EventCache<'_>,
+    activity_id: u32,
+    user: &BackendUser,
+    token: &Secret<String>,
+    attendance: &Attendance,
+) -> attendance_backend::Result<Vec<(AttendanceSession, attendance_backend::Result<()>)>> {
+    let sessions = cache
+        .attendance_sessions(activity_id, user, attendance)
+        .await?;
+    info!("Matching sessions: {:?}", sessions);
+
+    let mut results = Vec::new();
+    let mut cache_failed = sessions.from_cache && sessions.value.is_empty();
+    for session in sessions.value {
+        let result = backend
+            .mark(user, token, session.id, &attendance.password)
+            .await;
+
+        // the wrong password is wrong for everyone, no point in retrying
+        if !matches!(result, Ok(()) | Err(BackendError::WrongPassword)) && sessions.from_cache {
+            cache_failed = true;
+        }
+        results.push((session, result));
+    }
+
+    if !cache_failed {
+        return Ok(results);
+    }
+
+    warn!("The cached session data did not work for this user, fetching it");
+    // keep the successful marks, the sessions will not be available to this user anymore anyway
+    results.retain(|(_, result)| result.is_ok());
+
+    let sessions = backend
+        .list_sessions(activity_id, user, attendance)
+        .await?
+        .into_iter()
+        .filter_map(|entry| entry.session())
+        .collect::<Vec<_>>();
+    info!("Matching sessions: {:?}", sessions);
+
+    for session in sessions {
+        let result = backend
+            .mark(user, token, session.id, &attendance.password)
+            .await;
+        results.push((session, result));
+    }
+
+    Ok(results)
+}
+
+/// Everything needed to handle a single attendance password post
+struct PasswordEvent<'a> {
+    bot: &'a MyBot,
+    backend: &'a dyn AttendanceBackend,
+    cache: EventCache<'a>,
+    history: History<'a>,
+    activity_id: u32,
+    attendance: Attendance,
+}
+
 #[instrument(skip_all, err, fields(historia.state = ?state, tg.chat_id = %chat_id))]
 async fn handle_user(
-    bot: &MyBot,
-    moodle: &Moodle,
-    activity_id: u32,
+    event: &PasswordEvent<'_>,
     chat_id: ChatId,
     state: State,
-    attendance: &Attendance,
+    user: Option<BackendUser>,
 ) -> Result<()> {
-    match state {
-        State::Start => {
+    let &PasswordEvent {
+        bot,
+        backend,
+        ref cache,
+        ref history,
+        activity_id,
+        ref attendance,
+    } = event;
+
+    let user = match (state, user) {
+        (State::Registered, Some(user)) => user,
+        (State::ReceiveSession, _) => {
+            history
+                .record(chat_id, None, MarkOutcome::NotRegistered)
+                .await;
+            // don't interrupt the user
+            return Ok(());
+        }
+        (state, _) => {
+            if matches!(state, State::Registered) {
+                error!("The dialogue is registered, but the user is not stored");
+            }
+            history
+                .record(chat_id, None, MarkOutcome::NotRegistered)
+                .await;
             // missed attendance because not registered, suggest to register
             bot.send_message(
                 chat_id,
@@ -81,100 +278,207 @@ async fn handle_user(
                     attendance,
                     "you are not registered",
                     Solutions::Register,
-                    &moodle.make_attendance_url(activity_id)?,
+                    &backend.activity_url(activity_id)?,
                 ),
             )
             .await?;
+            return Ok(());
         }
-        State::ReceiveSession => {
-            // don't interrupt the user
+    };
+
+    metrics::MARKS_ATTEMPTED.inc();
+
+    let probe = backend.validate_user(&user).await;
+    history
+        .record_validation(chat_id, ValidationResult::of(&probe))
+        .await;
+
+    let probe = match probe {
+        Ok(probe) => probe,
+        Err(e) => {
+            error!("Failed to check user: {}", e);
+            history.record_error(chat_id, None, &e).await;
+            bot.send_message(
+                chat_id,
+                format_failure_message(
+                    attendance,
+                    &failure_reason(&e),
+                    failure_solution(&e),
+                    &backend.activity_url(activity_id)?,
+                ),
+            )
+            .await?;
+            return Ok(());
         }
-        State::Registered(user) => {
-            let SessionProbeResult::Valid { csrf_session, email } = moodle.check_user(&user).await?
-            else {
-                bot.send_message(
-                    chat_id,
-                    format_failure_message(attendance, "your session has become invalid", Solutions::ReRegister,
-                                           &moodle.make_attendance_url(activity_id)?),
-                ).await?;
-                return Ok(())
-            };
-
-            info!("Marking attendance for {}...", email);
-
-            let sessions = match moodle
-                .get_attendance_sessions(activity_id, &user)
-                .await
-                .map(|s| {
-                    s.into_iter()
-                        .filter(|s| s.matches(attendance))
-                        .collect::<Vec<_>>()
-                }) {
-                Ok(s) => s,
-                Err(e) => {
-                    error!("Failed to get attendance sessions: {}", e);
-                    bot.send_message(
-                        chat_id,
-                        format_failure_message(
-                            attendance,
-                            "I failed to get attendance sessions",
-                            Solutions::ManuallyMarkAt,
-                            &moodle.make_attendance_url(activity_id)?,
-                        ),
-                    )
-                    .await?;
-                    return Ok(());
-                }
-            };
+    };
+    let Validation::Valid { token, email } = probe else {
+        history
+            .record(chat_id, None, MarkOutcome::SessionInvalid)
+            .await;
+        bot.send_message(
+            chat_id,
+            format_failure_message(
+                attendance,
+                "your session has become invalid",
+                Solutions::ReRegister,
+                &backend.activity_url(activity_id)?,
+            ),
+        )
+        .await?;
+        return Ok(());
+    };
 
-            if sessions.is_empty() {
-                error!("No matching attendance sessions found");
-                bot.send_message(
-                    chat_id,
-                    format_failure_message(
-                        attendance,
-                        "I failed to find matching attendance session (or you are already marked)",
-                        Solutions::ManuallyMarkAt,
-                        &moodle.make_attendance_url(activity_id)?,
-                    ),
-                )
-                .await?;
-                return Ok(());
+    info!("Marking attendance for {}...", email);
+
+    let results = match mark_sessions(backend, cache, activity_id, &user, &token, attendance).await
+    {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Failed to get attendance sessions: {}", e);
+            history.record_error(chat_id, None, &e).await;
+            bot.send_message(
+                chat_id,
+                format_failure_message(
+                    attendance,
+                    &failure_reason(&e),
+                    failure_solution(&e),
+                    &backend.activity_url(activity_id)?,
+                ),
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+
+    if results.is_empty() {
+        // check whether the user has already been marked (manually or by a previous post)
+        let already_marked = match backend.list_sessions(activity_id, &user, attendance).await {
+            Ok(sessions) => sessions.iter().any(|e| e.present),
+            Err(e) => {
+                error!("Failed to get attendance report: {}", e);
+                false
             }
+        };
+
+        if already_marked {
+            info!("The user is already marked");
+            history
+                .record(chat_id, None, MarkOutcome::AlreadyMarked)
+                .await;
+            bot.send_message(
+                chat_id,
+                format!(
+                    "You are already marked as present on {}",
+                    bold(&format!("{:02}.{:02}", attendance.day, attendance.month))
+                ),
+            )
+            .await?;
+            return Ok(());
+        }
 
-            info!("Matching sessions: {:?}", sessions);
+        error!("No matching attendance sessions found");
+        history
+            .record(chat_id, None, MarkOutcome::NoMatchingSession)
+            .await;
+        bot.send_message(
+            chat_id,
+            format_failure_message(
+                attendance,
+                "I failed to find matching attendance session (or you are already marked)",
+                Solutions::ManuallyMarkAt,
+                &backend.activity_url(activity_id)?,
+            ),
+        )
+        .await?;
+        return Ok(());
+    }
 
-            for session in sessions {
-                match moodle
-                    .mark_attendance_session(&user, &csrf_session, session.id, &attendance.password)
-                    .await
-                {
-                    Ok(_) => {
+    for (session, result) in results {
+        match result {
+            Ok(_) => {
+                match confirm_marked(backend, activity_id, &user, attendance, &session).await {
+                    Ok(Some(entry)) => {
                         info!("Marked attendance for {}", email);
+                        history
+                            .record(chat_id, Some(session.id), MarkOutcome::Marked)
+                            .await;
                         bot.send_message(
                             chat_id,
                             format!(
-                                "Attendance on {} marked successfully!",
-                                bold(&format!("{:02}.{:02}", attendance.day, attendance.month))
+                                "Attendance on {} marked successfully!{}",
+                                bold(&format!("{:02}.{:02}", attendance.day, attendance.month)),
+                                entry
+                                    .points
+                                    .map(|p| format!(" Points: {}", escape(&p)))
+                                    .unwrap_or_default()
                             ),
                         )
                         .await?;
                     }
-                    Err(e) => {
-                        error!("Failed to mark attendance: {}", e);
+                    Ok(None) => {
+                        error!("Moodle accepted the mark, but did not record the status");
+                        history
+                            .record(
+                                chat_id,
+                                Some(session.id),
+                                MarkOutcome::Error(
+                                    "Moodle did not record the Present status".to_string(),
+                                ),
+                            )
+                            .await;
                         bot.send_message(
                             chat_id,
                             format_failure_message(
                                 attendance,
-                                "of some nasty error (contact the developer pls)",
+                                "moodle did not record you as present",
                                 Solutions::ManuallyMarkAt,
-                                &moodle.make_session_url(session.id)?,
+                                &backend.session_url(session.id)?,
                             ),
                         )
                         .await?;
                     }
+                    Err(e) => {
+                        error!("Failed to confirm the attendance mark: {}", e);
+                        history
+                            .record(
+                                chat_id,
+                                Some(session.id),
+                                MarkOutcome::Error(format!(
+                                    "Marked, but failed to confirm: {:#}",
+                                    e
+                                )),
+                            )
+                            .await;
+                        let url = backend.activity_url(activity_id)?;
+                        bot.send_message(
+                        chat_id,
+                        format!(
+                            "Attendance on {} is probably marked, but I could not check it. You should go & check your attendance\n\n{}",
+                            bold(&format!(
+                                "{:02}.{:02}",
+                                attendance.day, attendance.month
+                            )),
+                            link(url.as_str(), url.as_str())
+                        ),
+                    )
+                    .await?;
+                    }
                 }
             }
+            Err(e) => {
+                error!("Failed to mark attendance: {}", e);
+                history.record_error(chat_id, Some(session.id), &e).await;
+                bot.send_message(
+                    chat_id,
+                    format_failure_message(
+                        attendance,
+                        &failure_reason(&e),
+                        failure_solution(&e),
+                        &backend.session_url(session.id)?,
+                    ),
+                )
+                .await?;
+            }
         }
     }
 
@@ -184,7 +488,6 @@ async fn handle_user(
 #[instrument(skip_all, err, fields(
         tg.chat_id = %post.chat.id,
         tg.message_id = %post.id,
-        tg.message = %post.text().unwrap_or("<no text>"),
         historia.activity_id = tracing::field::Empty,
         historia.attendance.date = tracing::field::Empty,
         historia.attendance.password = tracing::field::Empty,
@@ -193,22 +496,20 @@ async fn handle_user(
 pub async fn channel_post(
     bot: MyBot,
     config: Arc<config::Bot>,
-    moodle: Arc<Moodle>,
+    backend: Arc<dyn AttendanceBackend>,
     post: Message,
     storage: Arc<MyStorage>,
 ) -> Result<()> {
     let span = tracing::Span::current();
 
-    let Some(&BotChannel {
-        activity_id,
-        ..
-    }) = config.update_channels.iter().find(|v| v.id == post.chat.id)
+    let Some(&BotChannel { activity_id, .. }) =
+        config.update_channels.iter().find(|v| v.id == post.chat.id)
     else {
         debug!("Received channel post from unknown chat: {:?}", post.chat);
         return Ok(());
     };
 
-    span.record("historia.activity_id", &activity_id);
+    span.record("historia.activity_id", activity_id);
 
     let Some(text) = post.text() else {
         debug!("Ignoring channel post without text: {:?}", post.id);
@@ -224,26 +525,85 @@ pub async fn channel_post(
 
     span.record(
         "historia.attendance.date",
-        &format!("{:02}.{:02}", attendance.day, attendance.month),
+        format!("{:02}.{:02}", attendance.day, attendance.month),
+    );
+    span.record(
+        "historia.attendance.password",
+        tracing::field::display(&attendance.password),
     );
-    span.record("historia.attendance.password", &attendance.password);
 
     info!("Received password: {}", attendance);
 
+    // same as with the outcomes, failing to write the history should not prevent us from marking
+    let event_id = match storage
+        .record_attendance(post.chat.id, post.id, activity_id, &attendance)
+        .await
+    {
+        Ok(event_id) => Some(event_id),
+        Err(e) => {
+            error!(
+                "Failed to record the attendance event, the history is disabled for it: {:?}",
+                e
+            );
+            None
+        }
+    };
+
+    let event = PasswordEvent {
+        bot: &bot,
+        backend: backend.as_ref(),
+        cache: EventCache::new(backend.as_ref()),
+        history: History {
+            storage: &storage,
+            event_id,
+            recorded: Default::default(),
+        },
+        activity_id,
+        attendance,
+    };
+
     let dialogues = storage.get_all_dialogues::<State>().await?;
-    info!("Found {} dialogues", dialogues.len());
+    let mut users: HashMap<_, _> = storage
+        .get_users()
+        .await?
+        .into_iter()
+        .map(|RegisteredUser { chat_id, user, .. }| (chat_id, user))
+        .collect();
+    // the users that left the channel are neither marked nor told that they are not registered
+    let unsubscribed = storage.get_unsubscribed_chats(post.chat.id).await?;
+    info!(
+        "Found {} dialogues, {} registered users, {} left the channel",
+        dialogues.len(),
+        users.len(),
+        unsubscribed.len()
+    );
 
-    for (chat_id, state) in dialogues {
-        if !chat_id.is_user() {
-            continue;
-        }
+    let dialogues = dialogues
+        .into_iter()
+        .filter(|(chat_id, _)| chat_id.is_user() && !unsubscribed.contains(chat_id))
+        .map(|(chat_id, state)| (chat_id, state, users.remove(&chat_id)))
+        .collect::<Vec<_>>();
 
-        if let Err(e) = handle_user(&bot, &moodle, activity_id, chat_id, state, &attendance).await {
-            error!("Failed to handle user {}: {:?}", chat_id, e);
-            // try to notify the user one last time
-            let _ = bot.send_message(chat_id, "Some really nasty error happened when trying to mark attendance for you. You should go & check your attendance").await;
-        }
-    }
+    // the users are handled concurrently, the moodle rate limit is still respected by the moodle client
+    stream::iter(dialogues)
+        .for_each_concurrent(config.mark_concurrency.get(), |(chat_id, state, user)| {
+            let event = &event;
+            async move {
+                if let Err(e) = handle_user(event, chat_id, state, user).await {
+                    error!("Failed to handle user {}: {:?}", chat_id, e);
+                    // the error may come after the outcome was recorded, e.g. when notifying the user
+                    if !event.history.has_recorded(chat_id) {
+                        event
+                            .history
+                            .record(chat_id, None, MarkOutcome::Error(format!("{:#}", e)))
+                            .await;
+                    }
+                    // try to notify the user one last time
+                    let _ = event.bot.send_message(chat_id, "Some really nasty error happened when trying to mark attendance for you. You should go & check your attendance").await;
+                }
+            }
+        })
+        .await;
 
     Ok(())
 }
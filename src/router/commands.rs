@@ -1,8 +1,10 @@
-use crate::moodle::{Moodle, SessionProbeResult};
+use crate::attendance::MarkOutcome;
+use crate::attendance_backend::{AttendanceBackend, BackendError, Validation};
 use crate::router::{MyDialogue, MyStorage, State};
+use crate::secret::Secret;
+use crate::storage::{RegisteredUser, ValidationResult};
 use crate::{config, MyBot};
 use anyhow::{Context, Result};
-use itertools::Itertools;
 use std::borrow::Cow;
 use std::sync::Arc;
 use teloxide::prelude::*;
@@ -12,14 +14,14 @@ use tracing::{error, info, instrument, warn};
 
 use super::Command;
 
-#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id, tg.message = %message.text().unwrap_or("<no text>")))]
+#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id))]
 pub async fn help(bot: MyBot, message: Message) -> Result<()> {
     info!("Received help command from {}", message.chat.id);
     bot.send_message(message.chat.id, Command::descriptions().to_string())
         .await?;
     Ok(())
 }
-#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id, tg.message = %message.text().unwrap_or("<no text>")))]
+#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id))]
 pub async fn start(
     bot: MyBot,
     moodle_config: Arc<config::Moodle>,
@@ -48,10 +50,11 @@ pub async fn start(
 
     Ok(())
 }
-#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id, tg.message = %message.text().unwrap_or("<no text>")))]
+#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id))]
 pub async fn status(
     bot: MyBot,
-    moodle: Arc<Moodle>,
+    backend: Arc<dyn AttendanceBackend>,
+    storage: Arc<MyStorage>,
     dialogue: MyDialogue,
     message: Message,
 ) -> Result<()> {
@@ -63,23 +66,52 @@ pub async fn status(
         State::ReceiveSession => {
             "You are not registered yet. Send me your moodle session cookie to register\n\n🚫 You will NOT be marked".into()
         }
-        State::Registered(user) => {
-            let result = moodle.check_user(&user).await;
+        State::Registered => {
+            let user = storage
+                .get_user(message.chat.id)
+                .await
+                .context("Getting user")?;
+            let Some(RegisteredUser { user, .. }) = user else {
+                error!("The dialogue is registered, but the user is not stored");
+                dialogue.update(State::Start).await?;
+                bot.send_message(
+                    message.chat.id,
+                    "You are not registered yet. Use /start to register\n\n🚫 You will NOT be marked",
+                )
+                .await?;
+                return Ok(());
+            };
+
+            let result = backend.validate_user(&user).await;
+            storage
+                .record_validation(message.chat.id, ValidationResult::of(&result))
+                .await?;
 
             match result {
-                Ok(SessionProbeResult::Valid { .. }) => {
+                Ok(Validation::Valid { .. }) => {
                     format!(
                         "You are registered as {}. You can use /reset to unregister\n\n✅ You WILL be marked",
                         user
                     )
                         .into()
                 }
-                Ok(SessionProbeResult::Invalid) => {
+                Ok(Validation::Invalid) => {
                     warn!("Session invalidated");
+                    storage.remove_user(message.chat.id).await?;
                     dialogue.update(State::Start).await?;
                     "You were registered, but your moodle session has expired. Use /start to re-register\n\n🚫 You will NOT be marked"
                         .into()
                 }
+                Err(BackendError::RateLimited) => {
+                    warn!("Rate limited while checking user");
+                    "Moodle is overloaded right now, so I could not check your session. Try again later\n\n⁉️ You will be marked MAYBE??"
+                        .into()
+                }
+                Err(e @ BackendError::Unexpected(_)) => {
+                    error!("Error while checking user: {}", e);
+                    "I could not understand the moodle profile page, please contact the bot admin\n\n⁉️ You will be marked MAYBE??"
+                        .into()
+                }
                 Err(e) => {
                     error!("Error while checking user: {}", e);
                     "An error occurred while checking your moodle session. Try again later\n\n⁉️ You will be marked MAYBE??"
@@ -93,50 +125,220 @@ pub async fn status(
 
     Ok(())
 }
-#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id, tg.message = %message.text().unwrap_or("<no text>")))]
+/// How many recent attendance passwords to show in the /history command
+const HISTORY_LENGTH: u32 = 10;
+
+#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id))]
+pub async fn history(bot: MyBot, storage: Arc<MyStorage>, message: Message) -> Result<()> {
+    info!("Received history command from {}", message.chat.id);
+
+    let history = storage
+        .get_history(message.chat.id, HISTORY_LENGTH)
+        .await
+        .context("Getting history")?;
+
+    if history.is_empty() {
+        bot.send_message(
+            message.chat.id,
+            "I haven't handled any attendance passwords for you yet",
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let mut text = format!("Your last {} attendance passwords:\n\n", history.len());
+    for entry in history {
+        let outcomes = entry
+            .outcomes
+            .into_iter()
+            .map(|outcome| -> Cow<_> {
+                match outcome {
+                    MarkOutcome::Marked => "✅ marked".into(),
+                    MarkOutcome::AlreadyMarked => "✅ already marked".into(),
+                    MarkOutcome::NoMatchingSession => {
+                        "❌ no open session found (already marked or closed)".into()
+                    }
+                    MarkOutcome::SessionInvalid => "❌ your moodle session was invalid".into(),
+                    MarkOutcome::NotRegistered => "🚫 skipped, you were not registered".into(),
+                    MarkOutcome::Error(e) => format!("❌ failed: {}", escape(&e)).into(),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        text.push_str(&format!(
+            "{} ({} UTC), password {}: {}\n",
+            bold(&format!(
+                "{:02}.{:02}",
+                entry.attendance.day, entry.attendance.month
+            )),
+            entry.recorded_at,
+            code_inline(entry.attendance.password.expose()),
+            outcomes
+        ));
+    }
+
+    bot.send_message(message.chat.id, text).await?;
+
+    Ok(())
+}
+
+#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id))]
+pub async fn channels(
+    bot: MyBot,
+    config: Arc<config::Bot>,
+    storage: Arc<MyStorage>,
+    message: Message,
+) -> Result<()> {
+    info!("Received channels command from {}", message.chat.id);
+
+    let left = storage
+        .get_left_channels(message.chat.id)
+        .await
+        .context("Getting left channels")?;
+
+    let mut text = "I take the attendance passwords from these channels:\n\n".to_string();
+    for channel in &config.update_channels {
+        let status = if left.contains(&channel.id) {
+            "🚫 left, you will NOT be marked"
+        } else {
+            "✅ joined, you will be marked"
+        };
+        text.push_str(&format!("{}: {}\n", code_inline(&channel.name), status));
+    }
+    text.push_str(&escape(
+        "\nUse /join <name> or /leave <name> to choose the channels you want to be marked for",
+    ));
+
+    bot.send_message(message.chat.id, text).await?;
+
+    Ok(())
+}
+
+#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id))]
+pub async fn join(
+    bot: MyBot,
+    config: Arc<config::Bot>,
+    storage: Arc<MyStorage>,
+    message: Message,
+    name: String,
+) -> Result<()> {
+    info!("Received join command from {}: {}", message.chat.id, name);
+    set_subscribed(bot, &config, &storage, &message, &name, true).await
+}
+
+#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id))]
+pub async fn leave(
+    bot: MyBot,
+    config: Arc<config::Bot>,
+    storage: Arc<MyStorage>,
+    message: Message,
+    name: String,
+) -> Result<()> {
+    info!("Received leave command from {}: {}", message.chat.id, name);
+    set_subscribed(bot, &config, &storage, &message, &name, false).await
+}
+
+async fn set_subscribed(
+    bot: MyBot,
+    config: &config::Bot,
+    storage: &MyStorage,
+    message: &Message,
+    name: &str,
+    subscribed: bool,
+) -> Result<()> {
+    let name = name.trim();
+    let Some(channel) = config
+        .update_channels
+        .iter()
+        .find(|channel| channel.name.eq_ignore_ascii_case(name))
+    else {
+        let text = if name.is_empty() {
+            "Tell me the name of the channel. Use /channels to see the channels I know".to_string()
+        } else {
+            format!(
+                "I don't know the channel {}. Use /channels to see the channels I know",
+                code_inline(name)
+            )
+        };
+        bot.send_message(message.chat.id, text).await?;
+        return Ok(());
+    };
+
+    storage
+        .set_subscribed(message.chat.id, channel.id, subscribed)
+        .await
+        .context("Updating the subscription")?;
+
+    let text = if subscribed {
+        format!(
+            "You joined {}\n\n✅ You WILL be marked using the passwords from it",
+            code_inline(&channel.name)
+        )
+    } else {
+        format!(
+            "You left {}\n\n🚫 You will NOT be marked using the passwords from it",
+            code_inline(&channel.name)
+        )
+    };
+    bot.send_message(message.chat.id, text).await?;
+
+    Ok(())
+}
+
+#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id))]
 pub async fn super_status(
     bot: MyBot,
-    moodle: Arc<Moodle>,
+    backend: Arc<dyn AttendanceBackend>,
     storage: Arc<MyStorage>,
     message: Message,
 ) -> Result<()> {
     info!("Received super_status command from {}", message.chat.id);
 
-    let mut status = String::new();
+    let chats = storage.get_chat_ids().await?;
+    let users = storage.get_users().await?;
 
-    for (chat, state) in storage
-        .get_all_dialogues::<State>()
-        .await?
-        .into_iter()
-        .sorted_by_key(|(chat, _)| *chat)
-    {
-        if !chat.is_user() {
-            continue;
-        }
+    let mut status = format!(
+        "{} registered users, {} chats in total\n\n",
+        users.len(),
+        chats.iter().filter(|chat| chat.is_user()).count()
+    );
 
-        let state: Cow<_> = match state {
-            State::Start => "[unregistered]".into(),
-            State::ReceiveSession => "[unregistered]".into(),
-            State::Registered(user) => {
-                let result = moodle.check_user(&user).await;
-
-                let result = match result {
-                    Ok(SessionProbeResult::Valid { .. }) => "VALID",
-                    Ok(SessionProbeResult::Invalid) => "INVAL",
-                    Err(e) => {
-                        error!("Error while checking user: {}", e);
-                        "ERROR"
-                    }
-                };
+    for RegisteredUser {
+        chat_id,
+        user,
+        registered_at,
+        last_validated_at,
+        last_validation_result,
+    } in users
+    {
+        let result = backend.validate_user(&user).await;
+        storage
+            .record_validation(chat_id, ValidationResult::of(&result))
+            .await?;
 
-                format!("{} [{}]", user, result).into()
+        let result = match result {
+            Ok(Validation::Valid { .. }) => "VALID",
+            Ok(Validation::Invalid) => "INVAL",
+            Err(BackendError::RateLimited) => "RLIMT",
+            Err(e) => {
+                error!("Error while checking user: {}", e);
+                "ERROR"
             }
         };
 
+        let previous = match (last_validation_result, last_validated_at) {
+            (Some(result), Some(at)) => format!("{} at {}", result.kind(), at),
+            _ => "never".to_string(),
+        };
+
         status.push_str(&format!(
             "{}: {}\n",
-            code_inline(&chat.to_string()),
-            escape(&state)
+            code_inline(&chat_id.to_string()),
+            escape(&format!(
+                "{} [{}] registered at {}, previous check: {}",
+                user, result, registered_at, previous
+            ))
         ));
     }
 
@@ -144,7 +346,7 @@ pub async fn super_status(
 
     Ok(())
 }
-#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id, tg.message = %message.text().unwrap_or("<no text>")))]
+#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id))]
 pub async fn tell(
     bot: MyBot,
     storage: Arc<MyStorage>,
@@ -156,8 +358,7 @@ pub async fn tell(
         message.chat.id, global_message
     );
 
-    let statuses = storage.get_all_dialogues::<State>().await?;
-    for &chat in statuses.keys() {
+    for chat in storage.get_chat_ids().await? {
         if chat.is_user() {
             bot.send_message(chat, &global_message).await?;
         }
@@ -165,22 +366,30 @@ pub async fn tell(
 
     Ok(())
 }
-#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id, tg.message = %message.text().unwrap_or("<no text>")))]
-pub async fn reset(bot: MyBot, dialogue: MyDialogue, message: Message) -> Result<()> {
+#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id))]
+pub async fn reset(
+    bot: MyBot,
+    storage: Arc<MyStorage>,
+    dialogue: MyDialogue,
+    message: Message,
+) -> Result<()> {
     info!("Received reset command from {}", message.chat.id);
     bot.send_message(
         message.chat.id,
         "Resetting the bot, you are no longer registered",
     )
     .await?;
+    storage.remove_user(message.chat.id).await?;
     dialogue.update(State::Start).await?;
     Ok(())
 }
 
-#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id, tg.message = %message.text().unwrap_or("<no text>")))]
+// the message is the session cookie, so it is not recorded
+#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id))]
 pub async fn receive_cookie(
     bot: MyBot,
-    moodle: Arc<Moodle>,
+    backend: Arc<dyn AttendanceBackend>,
+    storage: Arc<MyStorage>,
     dialogue: MyDialogue,
     message: Message,
 ) -> Result<()> {
@@ -192,11 +401,12 @@ pub async fn receive_cookie(
                 .await?;
 
             // TODO: check with regex and warn/error if it doesn't look like a session cookie
-            match moodle.make_user(session).await {
+            match backend.register(Secret::new(session)).await {
                 Ok(Some(user)) => {
                     let user_str = format!("{}", user);
 
-                    dialogue.update(State::Registered(user)).await?;
+                    storage.register_user(message.chat.id, &user).await?;
+                    dialogue.update(State::Registered).await?;
 
                     bot.edit_message_text(
                         message.chat.id,
@@ -235,7 +445,7 @@ pub async fn receive_cookie(
 
     Ok(())
 }
-#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id, tg.message = %message.text().unwrap_or("<no text>")))]
+#[instrument(skip_all, fields(tg.chat_id = %message.chat.id, tg.message_id = %message.id))]
 pub async fn invalid_state(bot: MyBot, message: Message) -> Result<()> {
     bot.send_message(
         message.chat.id,
@@ -1,5 +1,7 @@
 mod channel_post;
 mod commands;
+#[cfg(test)]
+mod tests;
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
@@ -11,8 +13,10 @@ use teloxide::prelude::*;
 use teloxide::utils::command::ParseError;
 
 use crate::config;
-use crate::moodle::MoodleUser;
-use crate::router::commands::{invalid_state, receive_cookie, status, super_status, tell};
+use crate::encrypted_serializer::Encrypted;
+use crate::router::commands::{
+    channels, history, invalid_state, join, leave, receive_cookie, status, super_status, tell,
+};
 use crate::storage::SqliteStorage;
 use channel_post::channel_post;
 use commands::{help, reset, start};
@@ -22,10 +26,11 @@ pub enum State {
     #[default]
     Start,
     ReceiveSession,
-    Registered(MoodleUser),
+    /// The user data is stored in the `users` table, see [`MyStorage::get_user`]
+    Registered,
 }
 
-pub type MyStorage = SqliteStorage<Json>;
+pub type MyStorage = SqliteStorage<Encrypted<Json>>;
 type MyDialogue = Dialogue<State, MyStorage>;
 
 fn parse_tell(msg: String) -> Result<(String,), ParseError> {
@@ -44,6 +49,16 @@ pub enum Command {
     Start,
     #[command(description = "check your token status.")]
     Status,
+    #[command(description = "show what happened with your recent attendance marks.")]
+    History,
+    #[command(description = "list the channels I take the attendance passwords from.")]
+    Channels,
+    #[command(description = "get marked for the passwords from a channel (/join <name>).")]
+    Join(String),
+    #[command(
+        description = "stop getting marked for the passwords from a channel (/leave <name>)."
+    )]
+    Leave(String),
     #[command(description = "off")]
     SuperStatus,
     #[command(description = "off", parse_with = parse_tell)]
@@ -63,6 +78,10 @@ pub fn schema(config: &config::Bot) -> UpdateHandler<anyhow::Error> {
         .branch(case![Command::Help].endpoint(help))
         .branch(case![Command::Start].endpoint(start))
         .branch(case![Command::Status].endpoint(status))
+        .branch(case![Command::History].endpoint(history))
+        .branch(case![Command::Channels].endpoint(channels))
+        .branch(case![Command::Join(name)].endpoint(join))
+        .branch(case![Command::Leave(name)].endpoint(leave))
         .branch(case![Command::Reset].endpoint(reset))
         .branch(
             dptree::filter(is_superuser)
@@ -0,0 +1,297 @@
+use super::*;
+use crate::attendance::MarkOutcome;
+use crate::attendance_backend::AttendanceBackend;
+use crate::config::{BotChannel, RedactionPolicy};
+use crate::fake_moodle::{self, FakeMoodle};
+use crate::fake_telegram::{BotRequest, FakeTelegram};
+use crate::storage::ValidationResult;
+use crate::{adapt_bot, MyBot};
+use camino::Utf8PathBuf;
+use std::num::NonZeroUsize;
+use std::ops::ControlFlow;
+use teloxide::adaptors::throttle::Limits;
+use teloxide::dispatching::dialogue::Storage;
+use teloxide::types::Me;
+use tempfile::TempDir;
+
+const USER: ChatId = ChatId(379529027);
+const OTHER_USER: ChatId = ChatId(42);
+const CHANNEL: ChatId = ChatId(-1001727873081);
+
+/// Runs the updates through [`schema`] with the same dependencies the dispatcher has,
+/// talking to a fake telegram and a fake moodle
+struct Harness {
+    telegram: FakeTelegram,
+    moodle: FakeMoodle,
+    bot: MyBot,
+    me: Me,
+    config: Arc<config::Bot>,
+    moodle_config: Arc<config::Moodle>,
+    backend: Arc<dyn AttendanceBackend>,
+    storage: Arc<MyStorage>,
+    _dir: TempDir,
+}
+
+impl Harness {
+    async fn new() -> Self {
+        let telegram = FakeTelegram::start().await;
+        let moodle = FakeMoodle::start().await;
+
+        let dir = TempDir::new().unwrap();
+        let database = config::Database {
+            path: Utf8PathBuf::from_path_buf(dir.path().join("storage.db")).unwrap(),
+        };
+        let storage = MyStorage::open(&database, Encrypted::new(Json, &Default::default()))
+            .await
+            .unwrap();
+
+        let config = Arc::new(config::Bot {
+            update_channels: vec![BotChannel {
+                id: CHANNEL,
+                name: "history".to_string(),
+                activity_id: fake_moodle::ACTIVITY_ID,
+            }],
+            super_users: vec![],
+            mark_concurrency: NonZeroUsize::new(4).unwrap(),
+            listener: Default::default(),
+        });
+        let backend = Arc::new(moodle.client().await);
+
+        Self {
+            // the fake telegram does not care about the rate limits
+            bot: adapt_bot(
+                telegram.bot(),
+                Limits {
+                    messages_per_sec_chat: 1000,
+                    messages_per_min_chat: 1000,
+                    messages_per_min_channel: 1000,
+                    messages_per_sec_overall: 1000,
+                },
+                RedactionPolicy::Strict,
+            ),
+            me: telegram.me(),
+            config,
+            moodle_config: Arc::new(moodle.config()),
+            backend,
+            telegram,
+            moodle,
+            storage,
+            _dir: dir,
+        }
+    }
+
+    async fn dispatch(&self, update: Update) {
+        let result = schema(&self.config)
+            .dispatch(dptree::deps![
+                self.bot.clone(),
+                self.me.clone(),
+                update,
+                self.config.clone(),
+                self.moodle_config.clone(),
+                self.storage.clone(),
+                self.backend.clone()
+            ])
+            .await;
+
+        match result {
+            ControlFlow::Break(result) => result.unwrap(),
+            ControlFlow::Continue(_) => panic!("the update was not handled"),
+        }
+    }
+
+    /// Sends a message from the user and returns the requests the bot made in response
+    async fn send(&self, chat_id: ChatId, text: &str) -> Vec<BotRequest> {
+        self.dispatch(self.telegram.message(chat_id, text)).await;
+        self.telegram.take_requests()
+    }
+
+    async fn post(&self, text: &str) -> Vec<BotRequest> {
+        self.post_to(CHANNEL, text).await
+    }
+
+    async fn post_to(&self, channel: ChatId, text: &str) -> Vec<BotRequest> {
+        self.dispatch(self.telegram.channel_post(channel, text))
+            .await;
+        self.telegram.take_requests()
+    }
+
+    async fn state(&self, chat_id: ChatId) -> Option<State> {
+        self.storage.clone().get_dialogue(chat_id).await.unwrap()
+    }
+
+    async fn register(&self, chat_id: ChatId) {
+        self.send(chat_id, "/start").await;
+        self.send(chat_id, fake_moodle::SESSION).await;
+    }
+}
+
+/// Returns the only request made by the bot
+fn single(requests: Vec<BotRequest>) -> BotRequest {
+    assert_eq!(requests.len(), 1, "{:#?}", requests);
+    requests.into_iter().next().unwrap()
+}
+
+#[tokio::test]
+async fn registers_user() {
+    let harness = Harness::new().await;
+
+    let reply = single(harness.send(USER, "/start").await);
+    assert_eq!(reply.method, "sendMessage");
+    assert_eq!(reply.chat_id(), Some(USER));
+    assert!(reply.text().starts_with("Let's start!"), "{}", reply.text());
+    assert!(matches!(
+        harness.state(USER).await,
+        Some(State::ReceiveSession)
+    ));
+
+    let requests = harness.send(USER, fake_moodle::SESSION).await;
+    assert_eq!(requests.len(), 2, "{:#?}", requests);
+    assert_eq!(requests[0].method, "sendMessage");
+    assert_eq!(requests[0].text(), "Checking session...");
+    assert_eq!(requests[1].method, "editMessageText");
+    assert_eq!(requests[1].chat_id(), Some(USER));
+    assert!(
+        requests[1].text().contains(fake_moodle::EMAIL),
+        "{}",
+        requests[1].text()
+    );
+
+    assert!(matches!(harness.state(USER).await, Some(State::Registered)));
+    let user = harness.storage.get_user(USER).await.unwrap().unwrap();
+    assert_eq!(user.user.email(), fake_moodle::EMAIL);
+    assert_eq!(user.user.session().expose(), fake_moodle::SESSION);
+}
+
+#[tokio::test]
+async fn rejects_invalid_cookie() {
+    let harness = Harness::new().await;
+
+    harness.send(USER, "/start").await;
+    let requests = harness.send(USER, "not-a-session").await;
+    assert_eq!(requests.len(), 2, "{:#?}", requests);
+    assert_eq!(requests[1].method, "editMessageText");
+    assert_eq!(requests[1].text(), "Invalid session cookie, try again");
+
+    assert!(matches!(
+        harness.state(USER).await,
+        Some(State::ReceiveSession)
+    ));
+    assert!(harness.storage.get_user(USER).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn reports_status() {
+    let harness = Harness::new().await;
+
+    let reply = single(harness.send(USER, "/status").await);
+    assert!(
+        reply.text().starts_with("You are not registered yet"),
+        "{}",
+        reply.text()
+    );
+
+    harness.register(USER).await;
+    let reply = single(harness.send(USER, "/status").await);
+    assert!(
+        reply
+            .text()
+            .starts_with(&format!("You are registered as {}", fake_moodle::EMAIL)),
+        "{}",
+        reply.text()
+    );
+
+    let user = harness.storage.get_user(USER).await.unwrap().unwrap();
+    assert_eq!(user.last_validation_result, Some(ValidationResult::Valid));
+}
+
+#[tokio::test]
+async fn resets_registration() {
+    let harness = Harness::new().await;
+    harness.register(USER).await;
+
+    let reply = single(harness.send(USER, "/reset").await);
+    assert_eq!(
+        reply.text(),
+        "Resetting the bot, you are no longer registered"
+    );
+
+    assert!(matches!(harness.state(USER).await, Some(State::Start)));
+    assert!(harness.storage.get_user(USER).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn marks_registered_users() {
+    let harness = Harness::new().await;
+    harness.register(USER).await;
+    // the other user is known to the bot, but not registered
+    harness.send(OTHER_USER, "/reset").await;
+
+    let mut requests = harness
+        .post(&format!(
+            "Attendance password for 14.02: {}",
+            fake_moodle::PASSWORD
+        ))
+        .await;
+    requests.sort_by_key(|r| r.chat_id());
+    assert_eq!(requests.len(), 2, "{:#?}", requests);
+
+    assert_eq!(requests[0].chat_id(), Some(OTHER_USER));
+    assert!(
+        requests[0].text().contains("you are not registered"),
+        "{}",
+        requests[0].text()
+    );
+
+    assert_eq!(requests[1].chat_id(), Some(USER));
+    assert!(
+        requests[1].text().contains("marked successfully")
+            && requests[1].text().contains("Points: 2 / 2"),
+        "{}",
+        requests[1].text()
+    );
+
+    assert_eq!(harness.moodle.marks().len(), 1);
+    let history = harness.storage.get_history(USER, 10).await.unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].outcomes, [MarkOutcome::Marked]);
+}
+
+#[tokio::test]
+async fn records_one_outcome_when_the_user_blocked_the_bot() {
+    let harness = Harness::new().await;
+    harness.register(USER).await;
+    harness.telegram.block(USER);
+
+    harness
+        .post(&format!(
+            "Attendance password for 14.02: {}",
+            fake_moodle::PASSWORD
+        ))
+        .await;
+
+    // the mark went through, only telling the user about it failed
+    assert_eq!(harness.moodle.marks().len(), 1);
+    let history = harness.storage.get_history(USER, 10).await.unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].outcomes, [MarkOutcome::Marked]);
+}
+
+#[tokio::test]
+async fn skips_users_that_left_the_channel() {
+    let harness = Harness::new().await;
+    harness.register(USER).await;
+    harness.send(OTHER_USER, "/reset").await;
+
+    let reply = single(harness.send(USER, "/leave history").await);
+    assert!(reply.text().starts_with("You left"), "{}", reply.text());
+    harness.send(OTHER_USER, "/leave history").await;
+
+    let requests = harness
+        .post(&format!(
+            "Attendance password for 14.02: {}",
+            fake_moodle::PASSWORD
+        ))
+        .await;
+    assert!(requests.is_empty(), "{:#?}", requests);
+    assert!(harness.moodle.marks().is_empty());
+}
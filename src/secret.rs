@@ -0,0 +1,105 @@
+use crate::config::RedactionPolicy;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Display};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REVEAL_PASSWORDS: AtomicBool = AtomicBool::new(false);
+
+/// Sets up what [`Secret`]s can be revealed in logs and traces. Should be called before anything is logged.
+pub fn set_policy(policy: RedactionPolicy) {
+    REVEAL_PASSWORDS.store(
+        matches!(policy, RedactionPolicy::RevealPasswords),
+        Ordering::Relaxed,
+    );
+}
+
+/// Decides whether a kind of [`Secret`] can be shown in logs and traces
+pub trait Visibility {
+    fn is_revealed() -> bool;
+}
+
+/// Session cookies and keys, never shown
+pub enum Credential {}
+
+impl Visibility for Credential {
+    fn is_revealed() -> bool {
+        false
+    }
+}
+
+/// Attendance passwords, shown only with [`RedactionPolicy::RevealPasswords`]
+pub enum AttendancePassword {}
+
+impl Visibility for AttendancePassword {
+    fn is_revealed() -> bool {
+        REVEAL_PASSWORDS.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that should not end up in logs and traces.
+///
+/// `Debug` and `Display` print a placeholder unless the [`Visibility`] allows it,
+/// the value itself can only be accessed with [`Secret::expose`]. Serializes as the bare value.
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret<T, V = Credential> {
+    value: T,
+    #[serde(skip)]
+    visibility: PhantomData<fn() -> V>,
+}
+
+pub type Password = Secret<String, AttendancePassword>;
+
+impl<T, V> Secret<T, V> {
+    pub fn new(value: T) -> Self {
+        Self {
+            value,
+            visibility: PhantomData,
+        }
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T, V> From<T> for Secret<T, V> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: Clone, V> Clone for Secret<T, V> {
+    fn clone(&self) -> Self {
+        Self::new(self.value.clone())
+    }
+}
+
+impl<T: Default, V> Default for Secret<T, V> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+const REDACTED: &str = "<redacted>";
+
+impl<T: Debug, V: Visibility> Debug for Secret<T, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if V::is_revealed() {
+            self.value.fmt(f)
+        } else {
+            f.write_str(REDACTED)
+        }
+    }
+}
+
+impl<T: Display, V: Visibility> Display for Secret<T, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if V::is_revealed() {
+            self.value.fmt(f)
+        } else {
+            f.write_str(REDACTED)
+        }
+    }
+}
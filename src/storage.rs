@@ -1,24 +1,125 @@
+use crate::attendance::{Attendance, MarkOutcome};
+use crate::attendance_backend::{self, BackendUser, Validation};
 use crate::config;
+use crate::encrypted_serializer::{Encrypted, EncryptedError};
+use crate::router::State;
+use crate::secret::Secret;
 use futures::future::BoxFuture;
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sqlx::migrate::Migrator;
 use sqlx::{sqlite::SqlitePool, Executor};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::{
     convert::Infallible,
     fmt::{Debug, Display},
     sync::Arc,
 };
+use teloxide::dispatching::dialogue::serializer::Json;
 use teloxide::dispatching::dialogue::{Serializer, Storage};
-use teloxide::types::ChatId;
+use teloxide::types::{ChatId, MessageId};
 use thiserror::Error;
 use tracing::{instrument, trace};
 
+/// Schema migrations from the `migrations` directory, applied in order when the storage is opened
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// A [`Serializer`] that knows which chat the dialogue belongs to, so that it can bind the data to it.
+pub trait ChatSerializer<D> {
+    type Error;
+
+    fn serialize(&self, chat_id: ChatId, val: &D) -> Result<Vec<u8>, Self::Error>;
+    fn deserialize(&self, chat_id: ChatId, data: &[u8]) -> Result<D, Self::Error>;
+}
+
+impl<D> ChatSerializer<D> for Json
+where
+    Json: Serializer<D>,
+{
+    type Error = <Json as Serializer<D>>::Error;
+
+    fn serialize(&self, _chat_id: ChatId, val: &D) -> Result<Vec<u8>, Self::Error> {
+        Serializer::serialize(self, val)
+    }
+
+    fn deserialize(&self, _chat_id: ChatId, data: &[u8]) -> Result<D, Self::Error> {
+        Serializer::deserialize(self, data)
+    }
+}
+
 /// A persistent dialogue storage based on [SQLite](https://www.sqlite.org/).
 pub struct SqliteStorage<S> {
     pool: SqlitePool,
     serializer: S,
 }
 
+/// A single mark outcome from the attendance history.
+#[derive(Debug)]
+pub struct HistoryEntry {
+    pub attendance: Attendance,
+    /// All outcomes recorded for the chat for this password, in the order they were recorded
+    pub outcomes: Vec<MarkOutcome>,
+    /// UTC timestamp of the last outcome in the `YYYY-MM-DD HH:MM:SS` format
+    pub recorded_at: String,
+}
+
+/// Result of the last moodle session check of a registered user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationResult {
+    Valid,
+    Invalid,
+    /// The session could not be checked
+    Error,
+}
+
+impl ValidationResult {
+    pub fn of(validation: &attendance_backend::Result<Validation>) -> Self {
+        match validation {
+            Ok(Validation::Valid { .. }) => ValidationResult::Valid,
+            Ok(Validation::Invalid) => ValidationResult::Invalid,
+            Err(_) => ValidationResult::Error,
+        }
+    }
+
+    /// Name of the result as stored in the database
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ValidationResult::Valid => "valid",
+            ValidationResult::Invalid => "invalid",
+            ValidationResult::Error => "error",
+        }
+    }
+
+    pub fn from_kind(kind: &str) -> Option<Self> {
+        Some(match kind {
+            "valid" => ValidationResult::Valid,
+            "invalid" => ValidationResult::Invalid,
+            "error" => ValidationResult::Error,
+            _ => return None,
+        })
+    }
+}
+
+/// A row of the `users` table
+#[derive(Debug)]
+pub struct RegisteredUser {
+    pub chat_id: ChatId,
+    pub user: BackendUser,
+    /// UTC timestamp in the `YYYY-MM-DD HH:MM:SS` format
+    pub registered_at: String,
+    pub last_validated_at: Option<String>,
+    pub last_validation_result: Option<ValidationResult>,
+}
+
+#[derive(sqlx::FromRow)]
+struct UserDbRow {
+    chat_id: i64,
+    email: String,
+    session: Vec<u8>,
+    registered_at: String,
+    last_validated_at: Option<String>,
+    last_validation_result: Option<String>,
+}
+
 /// An error returned from [`SqliteStorage`].
 #[derive(Debug, Error)]
 pub enum SqliteStorageError<SE>
@@ -31,6 +132,9 @@ where
     #[error("sqlite error: {0}")]
     SqliteError(#[from] sqlx::Error),
 
+    #[error("migration error: {0}")]
+    MigrationError(#[from] sqlx::migrate::MigrateError),
+
     /// Returned from [`SqliteStorage::remove_dialogue`].
     #[error("row not found")]
     DialogueNotFound,
@@ -42,17 +146,7 @@ impl<S> SqliteStorage<S> {
         serializer: S,
     ) -> Result<Arc<Self>, SqliteStorageError<Infallible>> {
         let pool = SqlitePool::connect(format!("sqlite:{}?mode=rwc", config.path).as_str()).await?;
-        let mut conn = pool.acquire().await?;
-        sqlx::query(
-            r#"
-CREATE TABLE IF NOT EXISTS teloxide_dialogues (
-    chat_id BIGINT PRIMARY KEY,
-    dialogue BLOB NOT NULL
-);
-        "#,
-        )
-        .execute(&mut conn)
-        .await?;
+        MIGRATOR.run(&pool).await?;
 
         Ok(Arc::new(Self { pool, serializer }))
     }
@@ -60,11 +154,11 @@ CREATE TABLE IF NOT EXISTS teloxide_dialogues (
 
 impl<S, D> Storage<D> for SqliteStorage<S>
 where
-    S: Send + Sync + Serializer<D> + 'static,
+    S: Send + Sync + ChatSerializer<D> + 'static,
     D: Send + Serialize + Debug + DeserializeOwned + 'static,
-    <S as Serializer<D>>::Error: Debug + Display,
+    <S as ChatSerializer<D>>::Error: Debug + Display,
 {
-    type Error = SqliteStorageError<<S as Serializer<D>>::Error>;
+    type Error = SqliteStorageError<<S as ChatSerializer<D>>::Error>;
 
     #[instrument(skip(self, chat_id), err, fields(tg.chat_id = %chat_id))]
     /// Returns [`sqlx::Error::RowNotFound`] if a dialogue does not exist.
@@ -97,7 +191,7 @@ where
         Box::pin(async move {
             let d = self
                 .serializer
-                .serialize(&dialogue)
+                .serialize(ChatId(chat_id), &dialogue)
                 .map_err(SqliteStorageError::SerdeError)?;
 
             self.pool
@@ -129,7 +223,7 @@ where
                 .await?
                 .map(|d| {
                     self.serializer
-                        .deserialize(&d)
+                        .deserialize(chat_id, &d)
                         .map_err(SqliteStorageError::SerdeError)
                 })
                 .transpose()
@@ -141,11 +235,11 @@ impl<S> SqliteStorage<S> {
     #[instrument(skip(self), err)]
     pub async fn get_all_dialogues<D>(
         &self,
-    ) -> Result<HashMap<ChatId, D>, SqliteStorageError<<S as Serializer<D>>::Error>>
+    ) -> Result<HashMap<ChatId, D>, SqliteStorageError<<S as ChatSerializer<D>>::Error>>
     where
-        S: Send + Sync + Serializer<D> + 'static,
+        S: Send + Sync + ChatSerializer<D> + 'static,
         D: Send + Serialize + Debug + DeserializeOwned + 'static,
-        <S as Serializer<D>>::Error: Debug + Display,
+        <S as ChatSerializer<D>>::Error: Debug + Display,
     {
         trace!("Requested all dialogues");
 
@@ -161,19 +255,492 @@ impl<S> SqliteStorage<S> {
             .map_err(SqliteStorageError::SqliteError)?
             .into_iter()
             .map(|row| {
-                let chat_id = row.chat_id;
+                let chat_id = ChatId(row.chat_id);
 
                 let dialogue = self
                     .serializer
-                    .deserialize(&row.dialogue)
+                    .deserialize(chat_id, &row.dialogue)
                     .map_err(SqliteStorageError::SerdeError)?;
 
-                Ok((ChatId(chat_id), dialogue))
+                Ok((chat_id, dialogue))
             })
             .collect::<Result<HashMap<_, _>, _>>()
     }
 }
 
+impl<S> SqliteStorage<Encrypted<S>> {
+    /// Encrypts the dialogues stored before the encryption was enabled, returning how many were encrypted.
+    #[instrument(skip(self), err)]
+    pub async fn encrypt_plaintext_dialogues(
+        &self,
+    ) -> Result<usize, SqliteStorageError<chacha20poly1305::Error>> {
+        #[derive(sqlx::FromRow)]
+        struct DialogueDbRow {
+            chat_id: i64,
+            dialogue: Vec<u8>,
+        }
+
+        let mut tx = self.pool.begin().await?;
+
+        let rows =
+            sqlx::query_as::<_, DialogueDbRow>("SELECT chat_id, dialogue FROM teloxide_dialogues")
+                .fetch_all(&mut tx)
+                .await?;
+
+        let mut encrypted = 0;
+        for row in rows {
+            if Encrypted::<S>::is_encrypted(&row.dialogue) {
+                continue;
+            }
+
+            let dialogue = self
+                .serializer
+                .encrypt(ChatId(row.chat_id), &row.dialogue)
+                .map_err(SqliteStorageError::SerdeError)?;
+            sqlx::query("UPDATE teloxide_dialogues SET dialogue = ? WHERE chat_id = ?")
+                .bind(dialogue)
+                .bind(row.chat_id)
+                .execute(&mut tx)
+                .await?;
+            encrypted += 1;
+        }
+
+        tx.commit().await?;
+
+        Ok(encrypted)
+    }
+}
+
+impl SqliteStorage<Encrypted<Json>> {
+    /// Moves the registrations stored in the dialogues before the `users` table was introduced into it,
+    /// leaving only the [`State::Registered`] pointer in the dialogue.
+    ///
+    /// Returns how many registrations were moved.
+    #[instrument(skip(self), err)]
+    pub async fn migrate_legacy_registrations(
+        &self,
+    ) -> Result<usize, SqliteStorageError<EncryptedError<serde_json::Error>>> {
+        #[derive(sqlx::FromRow)]
+        struct DialogueDbRow {
+            chat_id: i64,
+            dialogue: Vec<u8>,
+        }
+
+        /// The `Registered` dialogue state as it was serialized before
+        #[derive(Serialize, Deserialize)]
+        enum LegacyState {
+            Registered { session: String, email: String },
+        }
+
+        let mut tx = self.pool.begin().await?;
+
+        let rows =
+            sqlx::query_as::<_, DialogueDbRow>("SELECT chat_id, dialogue FROM teloxide_dialogues")
+                .fetch_all(&mut tx)
+                .await?;
+
+        let mut migrated = 0;
+        for row in rows {
+            // the other states are the same as before, so they fail to parse as the legacy one
+            let Ok(LegacyState::Registered { session, email }) = self
+                .serializer
+                .deserialize(ChatId(row.chat_id), &row.dialogue)
+            else {
+                continue;
+            };
+
+            let session = self
+                .serializer
+                .encrypt(ChatId(row.chat_id), session.as_bytes())
+                .map_err(|_| SqliteStorageError::SerdeError(EncryptedError::Encryption))?;
+            sqlx::query(
+                "INSERT INTO users (chat_id, email, session) VALUES (?, ?, ?) ON CONFLICT DO NOTHING",
+            )
+            .bind(row.chat_id)
+            .bind(email)
+            .bind(session)
+            .execute(&mut tx)
+            .await?;
+
+            let dialogue = self
+                .serializer
+                .serialize(ChatId(row.chat_id), &State::Registered)
+                .map_err(SqliteStorageError::SerdeError)?;
+            sqlx::query("UPDATE teloxide_dialogues SET dialogue = ? WHERE chat_id = ?")
+                .bind(dialogue)
+                .bind(row.chat_id)
+                .execute(&mut tx)
+                .await?;
+
+            migrated += 1;
+        }
+
+        tx.commit().await?;
+
+        Ok(migrated)
+    }
+}
+
+impl<S> SqliteStorage<Encrypted<S>> {
+    fn user_from_row(
+        &self,
+        row: UserDbRow,
+    ) -> Result<RegisteredUser, SqliteStorageError<chacha20poly1305::Error>> {
+        let session = self
+            .serializer
+            .decrypt(ChatId(row.chat_id), &row.session)
+            .map_err(SqliteStorageError::SerdeError)?;
+        let session = String::from_utf8(session)
+            .map_err(|_| SqliteStorageError::SerdeError(chacha20poly1305::Error))?;
+
+        Ok(RegisteredUser {
+            chat_id: ChatId(row.chat_id),
+            user: BackendUser::new(Secret::new(session), row.email),
+            registered_at: row.registered_at,
+            last_validated_at: row.last_validated_at,
+            last_validation_result: row
+                .last_validation_result
+                .as_deref()
+                .and_then(ValidationResult::from_kind),
+        })
+    }
+
+    /// Stores a freshly registered (and thus validated) user, replacing the previous registration
+    #[instrument(skip(self, chat_id, user), err, fields(tg.chat_id = %chat_id, moodle.user = %user))]
+    pub async fn register_user(
+        &self,
+        ChatId(chat_id): ChatId,
+        user: &BackendUser,
+    ) -> Result<(), SqliteStorageError<chacha20poly1305::Error>> {
+        let session = self
+            .serializer
+            .encrypt(ChatId(chat_id), user.session().expose().as_bytes())
+            .map_err(SqliteStorageError::SerdeError)?;
+
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO users (chat_id, email, session, registered_at, last_validated_at, last_validation_result)
+            VALUES (?, ?, ?, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP, ?)
+            "#,
+        )
+        .bind(chat_id)
+        .bind(user.email())
+        .bind(session)
+        .bind(ValidationResult::Valid.kind())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self, chat_id), err, fields(tg.chat_id = %chat_id))]
+    pub async fn get_user(
+        &self,
+        ChatId(chat_id): ChatId,
+    ) -> Result<Option<RegisteredUser>, SqliteStorageError<chacha20poly1305::Error>> {
+        sqlx::query_as::<_, UserDbRow>("SELECT * FROM users WHERE chat_id = ?")
+            .bind(chat_id)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|row| self.user_from_row(row))
+            .transpose()
+    }
+
+    /// Removes the user only if they are still registered with the given session, returning whether they were.
+    ///
+    /// The user may have registered again since the session was read, the new registration is kept then.
+    #[instrument(skip(self, chat_id, session), err, fields(tg.chat_id = %chat_id))]
+    pub async fn remove_user_with_session(
+        &self,
+        chat_id: ChatId,
+        session: &Secret<String>,
+    ) -> Result<bool, SqliteStorageError<chacha20poly1305::Error>> {
+        let stored: Option<Vec<u8>> =
+            sqlx::query_scalar("SELECT session FROM users WHERE chat_id = ?")
+                .bind(chat_id.0)
+                .fetch_optional(&self.pool)
+                .await?;
+        let Some(stored) = stored else {
+            return Ok(false);
+        };
+        let stored_session = self
+            .serializer
+            .decrypt(chat_id, &stored)
+            .map_err(SqliteStorageError::SerdeError)?;
+        if stored_session != session.expose().as_bytes() {
+            return Ok(false);
+        }
+
+        // the ciphertext is different for each registration, so a concurrent one is not deleted
+        let deleted = sqlx::query("DELETE FROM users WHERE chat_id = ? AND session = ?")
+            .bind(chat_id.0)
+            .bind(stored)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+
+        Ok(deleted > 0)
+    }
+
+    /// Returns all the registered users, ordered by the chat id
+    #[instrument(skip(self), err)]
+    pub async fn get_users(
+        &self,
+    ) -> Result<Vec<RegisteredUser>, SqliteStorageError<chacha20poly1305::Error>> {
+        sqlx::query_as::<_, UserDbRow>("SELECT * FROM users ORDER BY chat_id")
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|row| self.user_from_row(row))
+            .collect()
+    }
+}
+
+impl<S> SqliteStorage<S> {
+    /// Checks that the database is usable, for the readiness probe.
+    pub async fn ping(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self, chat_id), err, fields(tg.chat_id = %chat_id))]
+    pub async fn remove_user(&self, ChatId(chat_id): ChatId) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM users WHERE chat_id = ?")
+            .bind(chat_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self, chat_id), err, fields(tg.chat_id = %chat_id))]
+    pub async fn record_validation(
+        &self,
+        ChatId(chat_id): ChatId,
+        result: ValidationResult,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE users SET last_validated_at = CURRENT_TIMESTAMP, last_validation_result = ? WHERE chat_id = ?",
+        )
+        .bind(result.kind())
+        .bind(chat_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn count_users(&self) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM users")
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    pub async fn count_dialogues(&self) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM teloxide_dialogues")
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    /// Returns the chats that have a dialogue, without deserializing them
+    pub async fn get_chat_ids(&self) -> Result<Vec<ChatId>, sqlx::Error> {
+        Ok(
+            sqlx::query_scalar::<_, i64>("SELECT chat_id FROM teloxide_dialogues ORDER BY chat_id")
+                .fetch_all(&self.pool)
+                .await?
+                .into_iter()
+                .map(ChatId)
+                .collect(),
+        )
+    }
+
+    /// Subscribes the chat to the update channel or unsubscribes it
+    #[instrument(skip(self, chat_id), err, fields(tg.chat_id = %chat_id))]
+    pub async fn set_subscribed(
+        &self,
+        ChatId(chat_id): ChatId,
+        ChatId(channel_id): ChatId,
+        subscribed: bool,
+    ) -> Result<(), sqlx::Error> {
+        let query = if subscribed {
+            "DELETE FROM channel_opt_outs WHERE chat_id = ? AND channel_id = ?"
+        } else {
+            "INSERT OR IGNORE INTO channel_opt_outs (chat_id, channel_id) VALUES (?, ?)"
+        };
+        sqlx::query(query)
+            .bind(chat_id)
+            .bind(channel_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the update channels the chat has left
+    pub async fn get_left_channels(
+        &self,
+        ChatId(chat_id): ChatId,
+    ) -> Result<HashSet<ChatId>, sqlx::Error> {
+        Ok(sqlx::query_scalar::<_, i64>(
+            "SELECT channel_id FROM channel_opt_outs WHERE chat_id = ?",
+        )
+        .bind(chat_id)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(ChatId)
+        .collect())
+    }
+
+    /// Returns the chats that have left the update channel
+    pub async fn get_unsubscribed_chats(
+        &self,
+        ChatId(channel_id): ChatId,
+    ) -> Result<HashSet<ChatId>, sqlx::Error> {
+        Ok(sqlx::query_scalar::<_, i64>(
+            "SELECT chat_id FROM channel_opt_outs WHERE channel_id = ?",
+        )
+        .bind(channel_id)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(ChatId)
+        .collect())
+    }
+
+    /// Records a parsed attendance password, returning the id of the event to attach outcomes to.
+    #[instrument(skip(self, attendance), err, fields(tg.chat_id = %channel_id))]
+    pub async fn record_attendance(
+        &self,
+        channel_id: ChatId,
+        message_id: MessageId,
+        activity_id: u32,
+        attendance: &Attendance,
+    ) -> Result<i64, sqlx::Error> {
+        let id = sqlx::query(
+            r#"
+            INSERT INTO attendance_events (channel_id, message_id, activity_id, day, month, password)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(channel_id.0)
+        .bind(message_id.0)
+        .bind(activity_id)
+        .bind(attendance.day)
+        .bind(attendance.month)
+        .bind(attendance.password.expose())
+        .execute(&self.pool)
+        .await?
+        .last_insert_rowid();
+
+        Ok(id)
+    }
+
+    /// Records the outcome of marking attendance for a single chat (and, optionally, a single session).
+    #[instrument(skip(self, chat_id), err, fields(tg.chat_id = %chat_id))]
+    pub async fn record_outcome(
+        &self,
+        event_id: i64,
+        chat_id: ChatId,
+        session_id: Option<u32>,
+        outcome: &MarkOutcome,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        if let Some(session_id) = session_id {
+            sqlx::query("INSERT OR IGNORE INTO attendance_sessions VALUES (?, ?)")
+                .bind(event_id)
+                .bind(session_id)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO attendance_outcomes (event_id, chat_id, session_id, outcome, details)
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(event_id)
+        .bind(chat_id.0)
+        .bind(session_id)
+        .bind(outcome.kind())
+        .bind(outcome.details())
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await
+    }
+
+    /// Returns the outcomes for the last `limit` attendance passwords handled for the chat, most recent first.
+    #[instrument(skip(self, chat_id), err, fields(tg.chat_id = %chat_id))]
+    pub async fn get_history(
+        &self,
+        ChatId(chat_id): ChatId,
+        limit: u32,
+    ) -> Result<Vec<HistoryEntry>, sqlx::Error> {
+        #[derive(sqlx::FromRow)]
+        struct HistoryDbRow {
+            event_id: i64,
+            day: u8,
+            month: u8,
+            password: String,
+            outcome: String,
+            details: Option<String>,
+            created_at: String,
+        }
+
+        let rows = sqlx::query_as::<_, HistoryDbRow>(
+            r#"
+            SELECT o.event_id, e.day, e.month, e.password, o.outcome, o.details, o.created_at
+            FROM attendance_outcomes o
+            JOIN attendance_events e ON e.id = o.event_id
+            JOIN (
+                SELECT event_id, MAX(id) AS last_id
+                FROM attendance_outcomes
+                WHERE chat_id = ?
+                GROUP BY event_id
+                ORDER BY last_id DESC
+                LIMIT ?
+            ) recent ON recent.event_id = o.event_id
+            WHERE o.chat_id = ?
+            ORDER BY recent.last_id DESC, o.id
+            "#,
+        )
+        .bind(chat_id)
+        .bind(limit)
+        .bind(chat_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut result = Vec::<(i64, HistoryEntry)>::new();
+        for row in rows {
+            let outcome = MarkOutcome::from_kind(&row.outcome, row.details).unwrap_or_else(|| {
+                MarkOutcome::Error(format!("unknown outcome {:?}", row.outcome))
+            });
+
+            // the rows of an event come one after another
+            match result.last_mut() {
+                Some((event_id, entry)) if *event_id == row.event_id => {
+                    entry.outcomes.push(outcome);
+                    entry.recorded_at = row.created_at;
+                }
+                _ => result.push((
+                    row.event_id,
+                    HistoryEntry {
+                        attendance: Attendance {
+                            day: row.day,
+                            month: row.month,
+                            password: row.password.into(),
+                        },
+                        outcomes: vec![outcome],
+                        recorded_at: row.created_at,
+                    },
+                )),
+            }
+        }
+
+        Ok(result.into_iter().map(|(_, entry)| entry).collect())
+    }
+}
+
 async fn get_dialogue(
     pool: &SqlitePool,
     ChatId(chat_id): ChatId,
@@ -193,3 +760,303 @@ async fn get_dialogue(
 
     Ok(bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::State;
+    use camino::Utf8PathBuf;
+    use teloxide::dispatching::dialogue::serializer::Json;
+    use tempfile::TempDir;
+
+    /// Creates a database in a temporary directory, filled by the fixture script
+    async fn fixture_database(dir: &TempDir, fixture: Option<&str>) -> config::Database {
+        let path = Utf8PathBuf::from_path_buf(dir.path().join("storage.db")).unwrap();
+
+        if let Some(fixture) = fixture {
+            let pool = SqlitePool::connect(&format!("sqlite:{}?mode=rwc", path))
+                .await
+                .unwrap();
+            sqlx::query(fixture).execute(&pool).await.unwrap();
+            pool.close().await;
+        }
+
+        config::Database { path }
+    }
+
+    async fn applied_migrations<S>(storage: &SqliteStorage<S>) -> Vec<i64> {
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success ORDER BY version")
+            .fetch_all(&storage.pool)
+            .await
+            .unwrap()
+    }
+
+    fn all_migrations() -> Vec<i64> {
+        MIGRATOR.iter().map(|m| m.version).collect()
+    }
+
+    fn encrypted_json() -> Encrypted<Json> {
+        Encrypted::new(Json, &Default::default())
+    }
+
+    #[tokio::test]
+    async fn migrates_fresh_database() {
+        let dir = TempDir::new().unwrap();
+        let storage = SqliteStorage::open(&fixture_database(&dir, None).await, Json)
+            .await
+            .unwrap();
+
+        assert_eq!(applied_migrations(&storage).await, all_migrations());
+        assert!(storage
+            .get_all_dialogues::<State>()
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(storage.get_history(ChatId(1), 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn upgrades_dialogues_only_database() {
+        let dir = TempDir::new().unwrap();
+        let config = fixture_database(
+            &dir,
+            Some(include_str!("../fixtures/storage/dialogues_only.sql")),
+        )
+        .await;
+        let storage = SqliteStorage::open(&config, encrypted_json())
+            .await
+            .unwrap();
+
+        assert_eq!(applied_migrations(&storage).await, all_migrations());
+
+        // the legacy registrations are moved to the users table
+        assert_eq!(storage.migrate_legacy_registrations().await.unwrap(), 1);
+        let dialogues = storage.get_all_dialogues::<State>().await.unwrap();
+        assert_eq!(dialogues.len(), 2);
+        assert!(matches!(dialogues[&ChatId(379529027)], State::Registered));
+        assert!(matches!(dialogues[&ChatId(42)], State::ReceiveSession));
+
+        let users = storage.get_users().await.unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].chat_id, ChatId(379529027));
+        assert_eq!(users[0].user.to_string(), "user@innopolis.university");
+        assert_eq!(users[0].last_validation_result, None);
+
+        // the history tables are created by the migrations
+        let attendance = Attendance {
+            day: 14,
+            month: 2,
+            password: "qwerty".to_string().into(),
+        };
+        let event_id = storage
+            .record_attendance(ChatId(-1), MessageId(1), 87610, &attendance)
+            .await
+            .unwrap();
+        storage
+            .record_outcome(event_id, ChatId(42), None, &MarkOutcome::NotRegistered)
+            .await
+            .unwrap();
+        let history = storage.get_history(ChatId(42), 10).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].outcomes, [MarkOutcome::NotRegistered]);
+    }
+
+    #[tokio::test]
+    async fn legacy_registrations_are_moved_once() {
+        let dir = TempDir::new().unwrap();
+        let config = fixture_database(
+            &dir,
+            Some(include_str!("../fixtures/storage/dialogues_only.sql")),
+        )
+        .await;
+        let storage = SqliteStorage::open(&config, encrypted_json())
+            .await
+            .unwrap();
+
+        assert_eq!(storage.migrate_legacy_registrations().await.unwrap(), 1);
+        assert_eq!(storage.migrate_legacy_registrations().await.unwrap(), 0);
+        assert_eq!(storage.count_users().await.unwrap(), 1);
+
+        // the session is stored encrypted, but is still readable
+        let session: Vec<u8> = sqlx::query_scalar("SELECT session FROM users")
+            .fetch_one(&storage.pool)
+            .await
+            .unwrap();
+        assert!(Encrypted::<Json>::is_encrypted(&session));
+        let user = storage.get_user(ChatId(379529027)).await.unwrap().unwrap();
+        assert_eq!(user.user.email(), "user@innopolis.university");
+    }
+
+    #[tokio::test]
+    async fn registers_and_removes_users() {
+        let dir = TempDir::new().unwrap();
+        let storage = SqliteStorage::open(&fixture_database(&dir, None).await, encrypted_json())
+            .await
+            .unwrap();
+
+        let user = BackendUser::new(
+            Secret::new("session".to_string()),
+            "user@innopolis.university".to_string(),
+        );
+        storage.register_user(ChatId(1), &user).await.unwrap();
+        storage
+            .record_validation(ChatId(1), ValidationResult::Invalid)
+            .await
+            .unwrap();
+
+        let stored = storage.get_user(ChatId(1)).await.unwrap().unwrap();
+        assert_eq!(stored.user.session().expose(), "session");
+        assert_eq!(
+            stored.last_validation_result,
+            Some(ValidationResult::Invalid)
+        );
+        assert!(stored.last_validated_at.is_some());
+        assert_eq!(storage.get_chat_ids().await.unwrap(), Vec::<ChatId>::new());
+
+        // a stale session does not remove the registration
+        assert!(!storage
+            .remove_user_with_session(ChatId(1), &Secret::new("old-session".to_string()))
+            .await
+            .unwrap());
+        assert!(storage.get_user(ChatId(1)).await.unwrap().is_some());
+        assert!(storage
+            .remove_user_with_session(ChatId(1), user.session())
+            .await
+            .unwrap());
+        assert!(storage.get_user(ChatId(1)).await.unwrap().is_none());
+
+        storage.register_user(ChatId(1), &user).await.unwrap();
+        storage.remove_user(ChatId(1)).await.unwrap();
+        assert!(storage.get_user(ChatId(1)).await.unwrap().is_none());
+        assert_eq!(storage.count_users().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn upgrades_database_with_history() {
+        let dir = TempDir::new().unwrap();
+        let config = fixture_database(
+            &dir,
+            Some(include_str!("../fixtures/storage/with_history.sql")),
+        )
+        .await;
+        let storage = SqliteStorage::open(&config, Json).await.unwrap();
+
+        assert_eq!(applied_migrations(&storage).await, all_migrations());
+
+        let history = storage.get_history(ChatId(379529027), 10).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].outcomes, [MarkOutcome::Marked]);
+        assert_eq!(history[0].attendance.password.expose(), "qwerty");
+        assert_eq!(history[0].recorded_at, "2023-02-14 10:00:05");
+    }
+
+    #[tokio::test]
+    async fn history_is_limited_by_passwords() {
+        let dir = TempDir::new().unwrap();
+        let storage = SqliteStorage::open(&fixture_database(&dir, None).await, Json)
+            .await
+            .unwrap();
+        let (user, other_user) = (ChatId(1), ChatId(2));
+
+        let mut events = Vec::new();
+        for day in 1..=3 {
+            let attendance = Attendance {
+                day,
+                month: 3,
+                password: format!("password{}", day).into(),
+            };
+            events.push(
+                storage
+                    .record_attendance(ChatId(-100), MessageId(day as i32), 87610, &attendance)
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        let outcomes = [
+            (events[0], MarkOutcome::Marked),
+            (events[1], MarkOutcome::Error("moodle is down".to_string())),
+            (events[1], MarkOutcome::Marked),
+            (events[2], MarkOutcome::SessionInvalid),
+        ];
+        for (event_id, outcome) in outcomes {
+            storage
+                .record_outcome(event_id, user, None, &outcome)
+                .await
+                .unwrap();
+            storage
+                .record_outcome(event_id, other_user, None, &MarkOutcome::NotRegistered)
+                .await
+                .unwrap();
+        }
+
+        let history = storage.get_history(user, 2).await.unwrap();
+        let history = history
+            .iter()
+            .map(|entry| (entry.attendance.day, entry.outcomes.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            history,
+            [
+                (3, vec![MarkOutcome::SessionInvalid]),
+                (
+                    2,
+                    vec![
+                        MarkOutcome::Error("moodle is down".to_string()),
+                        MarkOutcome::Marked
+                    ]
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn tracks_channel_subscriptions() {
+        let dir = TempDir::new().unwrap();
+        let storage = SqliteStorage::open(&fixture_database(&dir, None).await, Json)
+            .await
+            .unwrap();
+        let (user, channel, other_channel) = (ChatId(1), ChatId(-100), ChatId(-200));
+
+        assert!(storage.get_left_channels(user).await.unwrap().is_empty());
+
+        storage.set_subscribed(user, channel, false).await.unwrap();
+        // leaving twice is fine
+        storage.set_subscribed(user, channel, false).await.unwrap();
+        assert_eq!(
+            storage.get_left_channels(user).await.unwrap(),
+            HashSet::from([channel])
+        );
+        assert_eq!(
+            storage.get_unsubscribed_chats(channel).await.unwrap(),
+            HashSet::from([user])
+        );
+        assert!(storage
+            .get_unsubscribed_chats(other_channel)
+            .await
+            .unwrap()
+            .is_empty());
+
+        storage.set_subscribed(user, channel, true).await.unwrap();
+        assert!(storage.get_left_channels(user).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reopening_does_not_rerun_migrations() {
+        let dir = TempDir::new().unwrap();
+        let config = fixture_database(&dir, None).await;
+
+        let storage = SqliteStorage::open(&config, Json).await.unwrap();
+        storage
+            .clone()
+            .update_dialogue(ChatId(1), State::ReceiveSession)
+            .await
+            .unwrap();
+        storage.pool.close().await;
+
+        let storage = SqliteStorage::open(&config, Json).await.unwrap();
+        assert_eq!(applied_migrations(&storage).await, all_migrations());
+        assert_eq!(storage.get_all_dialogues::<State>().await.unwrap().len(), 1);
+        assert_eq!(storage.count_dialogues().await.unwrap(), 1);
+    }
+}
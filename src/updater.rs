@@ -0,0 +1,159 @@
+use crate::attendance_backend::{AttendanceBackend, BackendUser, Validation};
+use crate::router::{MyStorage, State};
+use crate::storage::{RegisteredUser, ValidationResult};
+use crate::{config, MyBot};
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use teloxide::dispatching::dialogue::Storage;
+use teloxide::prelude::*;
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{error, info, instrument, warn};
+
+/// Periodically extends the sessions of all registered users and notifies those whose session has become invalid.
+pub async fn run(
+    config: config::Updater,
+    bot: MyBot,
+    storage: Arc<MyStorage>,
+    backend: Arc<dyn AttendanceBackend>,
+) {
+    // do not run the update right at the startup, the sessions were likely checked recently
+    let mut interval = tokio::time::interval_at(Instant::now() + config.interval, config.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = update_sessions(&bot, &storage, backend.as_ref()).await {
+            error!("Failed to update sessions: {:?}", e);
+        }
+    }
+}
+
+#[instrument(skip_all, err)]
+async fn update_sessions(
+    bot: &MyBot,
+    storage: &Arc<MyStorage>,
+    backend: &dyn AttendanceBackend,
+) -> Result<()> {
+    let users = storage.get_users().await?;
+    info!("Updating sessions, found {} users", users.len());
+
+    for RegisteredUser { chat_id, user, .. } in users {
+        if let Err(e) = update_user(bot, storage, backend, chat_id, user).await {
+            error!("Failed to update user {}: {:?}", chat_id, e);
+        }
+    }
+
+    Ok(())
+}
+
+#[instrument(skip_all, err, fields(tg.chat_id = %chat_id, moodle.user = %user))]
+async fn update_user(
+    bot: &MyBot,
+    storage: &Arc<MyStorage>,
+    backend: &dyn AttendanceBackend,
+    chat_id: ChatId,
+    user: BackendUser,
+) -> Result<()> {
+    match backend.keep_alive(&user).await {
+        Ok(true) => {}
+        Ok(false) => warn!("Could not extend the session, it is likely invalid"),
+        // the extender is not critical, the session may still be valid
+        Err(e) => warn!("Failed to extend session: {:?}", e),
+    }
+
+    let probe = backend.validate_user(&user).await;
+    storage
+        .record_validation(chat_id, ValidationResult::of(&probe))
+        .await?;
+
+    match probe.context("Checking user")? {
+        Validation::Valid { .. } => {
+            info!("Session is still valid");
+        }
+        Validation::Invalid => {
+            // the user may have registered again while we were checking the old session
+            if !storage
+                .remove_user_with_session(chat_id, user.session())
+                .await?
+            {
+                info!("Session invalidated, but the user has registered again since");
+                return Ok(());
+            }
+
+            warn!("Session invalidated, notifying the user");
+            storage
+                .clone()
+                .update_dialogue(chat_id, State::Start)
+                .await?;
+            bot.send_message(
+                chat_id,
+                "Your moodle session has expired, so I will NOT be able to mark your attendance.\n\nUse /start to re-register",
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RedactionPolicy;
+    use crate::encrypted_serializer::Encrypted;
+    use crate::fake_moodle::{self, FakeMoodle};
+    use crate::fake_telegram::FakeTelegram;
+    use crate::secret::Secret;
+    use crate::{adapt_bot, config};
+    use camino::Utf8PathBuf;
+    use teloxide::adaptors::throttle::Limits;
+    use teloxide::dispatching::dialogue::serializer::Json;
+    use tempfile::TempDir;
+
+    const USER: ChatId = ChatId(379529027);
+
+    #[tokio::test]
+    async fn keeps_registration_made_during_the_update() {
+        let telegram = FakeTelegram::start().await;
+        let moodle = FakeMoodle::start().await;
+        let backend = moodle.client().await;
+        let bot = adapt_bot(telegram.bot(), Limits::default(), RedactionPolicy::Strict);
+
+        let dir = TempDir::new().unwrap();
+        let database = config::Database {
+            path: Utf8PathBuf::from_path_buf(dir.path().join("storage.db")).unwrap(),
+        };
+        let storage = MyStorage::open(&database, Encrypted::new(Json, &Default::default()))
+            .await
+            .unwrap();
+
+        // the update has read the expired session, then the user registers with a new one
+        let stale = BackendUser::new(
+            Secret::new("expired-session".to_string()),
+            fake_moodle::EMAIL.to_string(),
+        );
+        let fresh = BackendUser::new(
+            Secret::new(fake_moodle::SESSION.to_string()),
+            fake_moodle::EMAIL.to_string(),
+        );
+        storage.register_user(USER, &fresh).await.unwrap();
+        storage
+            .clone()
+            .update_dialogue(USER, State::Registered)
+            .await
+            .unwrap();
+
+        update_user(&bot, &storage, &backend, USER, stale)
+            .await
+            .unwrap();
+
+        let user = storage.get_user(USER).await.unwrap().unwrap();
+        assert_eq!(user.user.session().expose(), fake_moodle::SESSION);
+        assert!(matches!(
+            storage.clone().get_dialogue(USER).await.unwrap(),
+            Some(State::Registered)
+        ));
+        assert!(telegram.take_requests().is_empty());
+    }
+}